@@ -1,9 +1,11 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::path::PathBuf;
 use tokio::time::Instant;
+use uuid::Uuid;
 use zeroize::Zeroizing;
 
-use crate::AgentState;
+use crate::{AgentState, models::PendingUpload};
 
 impl AgentState {
     pub fn new() -> Self {
@@ -11,6 +13,7 @@ impl AgentState {
             vault_key: None,
             vault_path: None,
             last_activity: None,
+            uploads: HashMap::new(),
         }
     }
 
@@ -21,6 +24,11 @@ impl AgentState {
         Ok(())
     }
 
+    pub fn replace_key(&mut self, key: [u8; 32]) -> Result<(), Box<dyn Error>> {
+        self.vault_key = Some(Zeroizing::new(key.to_vec()));
+        Ok(())
+    }
+
     pub fn lock_vault(&mut self) -> Result<(), Box<dyn Error>> {
         if self.vault_key.is_some() {
             self.vault_key = None;
@@ -31,6 +39,36 @@ impl AgentState {
         if self.last_activity.is_some() {
             self.last_activity = None;
         }
+        self.uploads.clear();
         Ok(())
     }
+
+    /// The upload with this ID, unless another connection started it.
+    pub fn upload_mut(&mut self, upload: Uuid, connection: u64) -> Option<&mut PendingUpload> {
+        self.uploads
+            .get_mut(&upload)
+            .filter(|pending| pending.connection == connection)
+    }
+
+    pub fn pending_uploads(&self, connection: u64) -> usize {
+        self.uploads
+            .values()
+            .filter(|upload| upload.connection == connection)
+            .count()
+    }
+}
+
+impl PendingUpload {
+    /// Appends a chunk, growing the buffer only as data arrives. The buffer is
+    /// moved by hand so the copy left behind gets zeroed too.
+    pub fn push(&mut self, chunk: &[u8]) {
+        let needed = self.data.len() + chunk.len();
+        if needed > self.data.capacity() {
+            let capacity = needed.max(self.data.capacity() * 2).min(self.size as usize);
+            let mut data = Zeroizing::new(Vec::with_capacity(capacity));
+            data.extend_from_slice(&self.data);
+            self.data = data;
+        }
+        self.data.extend_from_slice(chunk);
+    }
 }
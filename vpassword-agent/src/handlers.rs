@@ -1,28 +1,71 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        self, Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::UnixStream,
-    sync::Mutex,
+    sync::{Mutex, mpsc, oneshot},
     time::Instant,
 };
 
-use vpassword_core::models::{Request, Response, Vault};
+use uuid::Uuid;
+use vpassword_core::{
+    attachment::{ATTACHMENT_TRANSFER_CHUNK, MAX_ATTACHMENT_SIZE},
+    errors::{ResponseError, VaultError},
+    models::{EntrySummary, KeySlotKind, Request, Response, Vault},
+    protocol::{
+        ClientMessage, Hello, TAGGED_PROTOCOL_VERSION, TaggedResponse, receive_message,
+        send_message,
+    },
+};
+use zeroize::Zeroizing;
+
+use crate::{AgentState, models::PendingUpload, peer::Peer};
+
+/// How many attachment uploads one connection may have going at once.
+const MAX_PENDING_UPLOADS: usize = 4;
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(0);
 
-use crate::AgentState;
+/// The requests of one connection that haven't been answered yet, by client ID.
+#[derive(Default)]
+struct InFlight {
+    /// Each also gets a sequence number of our own, so that a cancelled request
+    /// still in the channel can't be mistaken for a later one reusing its ID.
+    queued: HashMap<u64, u64>,
+    /// The request being handled, which can still be cancelled while it
+    /// waits for the agent to be free.
+    running: Option<(u64, oneshot::Sender<()>)>,
+}
+
+impl InFlight {
+    fn contains(&self, id: u64) -> bool {
+        self.queued.contains_key(&id)
+            || self
+                .running
+                .as_ref()
+                .is_some_and(|(running, _)| *running == id)
+    }
+}
 
 // TODO: expiration time
 // TODO: better handling of vault state
-async fn handle_request(request: Request, state: Arc<Mutex<AgentState>>) -> Response {
+async fn handle_request(
+    request: Request,
+    connection: u64,
+    state: Arc<Mutex<AgentState>>,
+) -> Response {
     let mut guard = state.lock().await;
     if guard.last_activity.is_some() {
         let time_since_last_activity = guard.last_activity.unwrap().elapsed().as_secs();
         if time_since_last_activity >= 300 {
             return match guard.lock_vault() {
-                Ok(_) => {
-                    Response::Error("Session timed out. You need to run open again.".to_string())
-                }
-                Err(e) => Response::Error(e.to_string()),
+                Ok(_) => Response::Error(ResponseError::SessionExpired),
+                Err(e) => Response::Error(ResponseError::Other(e.to_string())),
             };
         } else {
             guard.last_activity = Some(Instant::now())
@@ -30,101 +73,938 @@ async fn handle_request(request: Request, state: Arc<Mutex<AgentState>>) -> Resp
     }
 
     match request {
+        Request::Hello { hello } => greet(&hello),
         Request::UnlockVault {
             vault_path,
             master_password,
         } => {
             if guard.vault_key.is_some() {
-                return Response::Error("a vault is already open".to_string());
+                return Response::Error(ResponseError::AlreadyOpen);
             }
-            let vault_key = match Vault::new_from_file(&vault_path) {
-                Ok(v) => match v.unlock_and_get_key(master_password.as_ref()) {
-                    Ok(key) => key,
-                    Err(e) => return Response::Error(e.to_string()),
-                },
-                Err(e) => return Response::Error(e.to_string()),
+            let mut vault = match Vault::new_from_file(&vault_path) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            let vault_key = match vault.unlock_and_get_key(master_password.as_ref()) {
+                Ok(key) => key,
+                Err(e) => return Response::Error(e.into()),
             };
+            upgrade_key_slot(
+                &mut vault,
+                &vault_key,
+                KeySlotKind::MasterPassword,
+                &master_password,
+            );
+            purge_expired_trash(&mut vault, &vault_key);
             return match guard.unlock_vault(vault_path, vault_key) {
-                Ok(_) => {
-                    println!("sending back unlcok");
-                    Response::Ok
-                }
-                Err(e) => Response::Error(e.to_string()),
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(ResponseError::Other(e.to_string())),
+            };
+        }
+        Request::UnlockVaultWithKeySlot {
+            vault_path,
+            kind,
+            secret,
+        } => {
+            if guard.vault_key.is_some() {
+                return Response::Error(ResponseError::AlreadyOpen);
+            }
+            let mut vault = match Vault::new_from_file(&vault_path) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            let vault_key = match vault.unlock_with_key_slot(kind, secret.as_ref()) {
+                Ok(key) => key,
+                Err(e) => return Response::Error(e.into()),
+            };
+            upgrade_key_slot(&mut vault, &vault_key, kind, &secret);
+            purge_expired_trash(&mut vault, &vault_key);
+            return match guard.unlock_vault(vault_path, vault_key) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(ResponseError::Other(e.to_string())),
+            };
+        }
+        Request::AddKeySlot { kind, secret } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            let vault_key = guard.vault_key.as_ref().unwrap();
+            let argon2 = vault.key_slot_params();
+            match vault
+                .change_header(vault_key, |vault| {
+                    vault.add_key_slot(vault_key, kind, secret.as_ref(), argon2)
+                })
+                .and_then(|_| vault.save_to_file())
+            {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::RemoveKeySlot { kind } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
             };
+            match vault
+                .change_header(guard.vault_key.as_ref().unwrap(), |vault| {
+                    vault.remove_key_slot(kind)
+                })
+                .and_then(|_| vault.save_to_file())
+            {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
         }
         Request::LockVault => {
             return match guard.lock_vault() {
                 Ok(_) => Response::Ok,
-                Err(e) => Response::Error(e.to_string()),
+                Err(e) => Response::Error(ResponseError::Other(e.to_string())),
             };
         }
-        Request::ListEntries => {
-            if guard.vault_key.is_some() {
-                let vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
-                    Ok(vault) => vault,
-                    Err(e) => return Response::Error(e.to_string()),
+        Request::ChangeMasterPassword {
+            old_password,
+            new_password,
+        } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            let vault_key =
+                match vault.change_master_password(old_password.as_ref(), new_password.as_ref()) {
+                    Ok(key) => key,
+                    Err(e) => return Response::Error(e.into()),
                 };
-                match vault.list(guard.vault_key.as_ref().unwrap()) {
-                    Ok(list) => Response::PasswordList { list },
-                    Err(e) => Response::Error(e.to_string()),
+            match guard.replace_key(vault_key) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(ResponseError::Other(e.to_string())),
+            }
+        }
+        Request::ListEntries => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.list(guard.vault_key.as_ref().unwrap()) {
+                Ok(list) => Response::EntryList {
+                    entries: list.passwords.iter().map(EntrySummary::from).collect(),
+                    folders: list.all_folders().into_iter().collect(),
+                },
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::GetOtp { entry } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.generate_otp(guard.vault_key.as_ref().unwrap(), &entry) {
+                Ok(otp) => Response::OtpCode { otp },
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::StartAttachmentUpload { entry, name, size } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            if size > MAX_ATTACHMENT_SIZE {
+                return Response::Error(VaultError::AttachmentTooLarge(MAX_ATTACHMENT_SIZE).into());
+            }
+            if guard.pending_uploads(connection) >= MAX_PENDING_UPLOADS {
+                return Response::Error(ResponseError::Other(
+                    "Too many uploads in progress".to_string(),
+                ));
+            }
+            let vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            // fail before any data is sent rather than after
+            match vault.list(guard.vault_key.as_ref().unwrap()) {
+                Ok(list) if list.position(&entry).is_none() => {
+                    return Response::Error(VaultError::NoSuchEntry(entry).into());
                 }
-            } else {
-                Response::Error("No vault is open".to_string())
+                Ok(_) => {}
+                Err(e) => return Response::Error(e.into()),
+            }
+            let upload = Uuid::new_v4();
+            guard.uploads.insert(
+                upload,
+                PendingUpload {
+                    connection,
+                    entry,
+                    name,
+                    size,
+                    data: Zeroizing::new(Vec::new()),
+                },
+            );
+            Response::UploadStarted { upload }
+        }
+        Request::UploadAttachmentChunk { upload, data } => {
+            let data = Zeroizing::new(data);
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let Some(pending) = guard.upload_mut(upload, connection) else {
+                return Response::Error(ResponseError::Other("No such upload".to_string()));
+            };
+            if pending.data.len() + data.len() > pending.size as usize {
+                guard.uploads.remove(&upload);
+                return Response::Error(ResponseError::Other(
+                    "Upload is larger than announced".to_string(),
+                ));
+            }
+            pending.push(&data);
+            Response::Ok
+        }
+        Request::FinishAttachmentUpload { upload } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            if guard.upload_mut(upload, connection).is_none() {
+                return Response::Error(ResponseError::Other("No such upload".to_string()));
+            }
+            let pending = guard.uploads.remove(&upload).unwrap();
+            if pending.data.len() as u64 != pending.size {
+                return Response::Error(ResponseError::Other("Upload is incomplete".to_string()));
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.add_attachment(
+                guard.vault_key.as_ref().unwrap(),
+                &pending.entry,
+                &pending.name,
+                &pending.data,
+            ) {
+                Ok(attachment) => Response::Attachment { attachment },
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::GetAttachmentChunk {
+            entry,
+            attachment,
+            offset,
+        } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.read_attachment(
+                guard.vault_key.as_ref().unwrap(),
+                &entry,
+                &attachment,
+                offset,
+                ATTACHMENT_TRANSFER_CHUNK,
+            ) {
+                Ok((data, size)) => Response::AttachmentChunk {
+                    data: data.to_vec(),
+                    size,
+                },
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::RemoveAttachment { entry, attachment } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.remove_attachment(guard.vault_key.as_ref().unwrap(), &entry, &attachment) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::SearchEntries { query, filters } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.list(guard.vault_key.as_ref().unwrap()) {
+                Ok(list) => Response::EntrySummaries {
+                    entries: list.search(&query, &filters),
+                },
+                Err(e) => Response::Error(e.into()),
             }
         }
-        Request::GetEntry { name } => {
+        Request::GetEntry { entry } => {
             if guard.vault_key.is_none() {
-                return Response::Error("No vault is open".to_string());
+                return Response::Error(ResponseError::Locked);
             }
             let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
                 Ok(vault) => vault,
-                Err(e) => return Response::Error(e.to_string()),
+                Err(e) => return Response::Error(e.into()),
             };
-            match vault.get_entry(guard.vault_key.as_ref().unwrap(), name.as_ref()) {
-                Ok(entry) => Response::PasswordEntry { entry },
-                Err(e) => Response::Error(e.to_string()),
+            match vault.get_entry(guard.vault_key.as_ref().unwrap(), entry.as_ref()) {
+                Ok(entry) => Response::PasswordEntry {
+                    entry: Box::new(entry),
+                },
+                Err(e) => Response::Error(e.into()),
             }
         }
         Request::AddEntry { entry } => {
             if guard.vault_key.is_none() {
-                return Response::Error("No vault is open".to_string());
+                return Response::Error(ResponseError::Locked);
             }
             let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
                 Ok(vault) => vault,
-                Err(e) => return Response::Error(e.to_string()),
+                Err(e) => return Response::Error(e.into()),
             };
             match vault.add_entry(guard.vault_key.as_ref().unwrap(), entry) {
                 Ok(_) => Response::Ok,
-                Err(e) => Response::Error(e.to_string()),
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::ListBackups => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.backups() {
+                Ok(backups) => Response::BackupList { backups },
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::RestoreBackup { backup } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            if let Err(e) = vault.restore_backup(&backup) {
+                return Response::Error(e.into());
+            }
+            // a backup from before the data key was introduced has a different key
+            if vault.list(guard.vault_key.as_ref().unwrap()).is_err() {
+                return match guard.lock_vault() {
+                    Ok(_) => Response::Error(ResponseError::Other(
+                        "Backup restored, but it needs to be opened again".to_string(),
+                    )),
+                    Err(e) => Response::Error(ResponseError::Other(e.to_string())),
+                };
+            }
+            Response::Ok
+        }
+        Request::SetBackupPolicy { policy } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.set_backup_policy(guard.vault_key.as_ref().unwrap(), policy) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
             }
         }
-        Request::RemoveEntry { name } => {
+        Request::SetKdfPolicy { policy } => {
             if guard.vault_key.is_none() {
-                return Response::Error("No vault is open".to_string());
+                return Response::Error(ResponseError::Locked);
             }
             let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
                 Ok(vault) => vault,
-                Err(e) => return Response::Error(e.to_string()),
+                Err(e) => return Response::Error(e.into()),
             };
-            match vault.remove_entry(guard.vault_key.as_ref().unwrap(), name.as_ref()) {
+            match vault.set_kdf_policy(guard.vault_key.as_ref().unwrap(), policy) {
                 Ok(_) => Response::Ok,
-                Err(e) => Response::Error(e.to_string()),
+                Err(e) => Response::Error(e.into()),
             }
         }
+        Request::ListTrash => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.trash(guard.vault_key.as_ref().unwrap()) {
+                Ok(entries) => Response::TrashList { entries },
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::RestoreEntry { entry } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.restore_entry(guard.vault_key.as_ref().unwrap(), &entry) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::PurgeTrash { entry } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.purge_trash(guard.vault_key.as_ref().unwrap(), entry.as_deref()) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::SetTrashRetention { days } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.set_trash_retention(guard.vault_key.as_ref().unwrap(), days) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::RemoveEntry { entry } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.remove_entry(guard.vault_key.as_ref().unwrap(), entry.as_ref()) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::UpdateEntry { entry, update } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.update_entry(guard.vault_key.as_ref().unwrap(), entry.as_ref(), update) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::RestorePassword { entry, index } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.restore_password(guard.vault_key.as_ref().unwrap(), entry.as_ref(), index) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::RenameEntry { entry, new_name } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.rename_entry(
+                guard.vault_key.as_ref().unwrap(),
+                entry.as_ref(),
+                new_name.as_ref(),
+            ) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::MoveEntry { entry, folder } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.move_entry(guard.vault_key.as_ref().unwrap(), &entry, &folder) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::CreateFolder { path } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.create_folder(guard.vault_key.as_ref().unwrap(), &path) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::MoveFolder { from, to } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.move_folder(guard.vault_key.as_ref().unwrap(), &from, &to) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+        Request::DeleteFolder { path } => {
+            if guard.vault_key.is_none() {
+                return Response::Error(ResponseError::Locked);
+            }
+            let mut vault = match Vault::new_from_file(guard.vault_path.as_ref().unwrap()) {
+                Ok(vault) => vault,
+                Err(e) => return Response::Error(e.into()),
+            };
+            match vault.delete_folder(guard.vault_key.as_ref().unwrap(), &path) {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            }
+        }
+    }
+}
+
+// a failed upgrade leaves the old, still valid slot in place, so it must not fail the unlock
+fn upgrade_key_slot(vault: &mut Vault, vault_key: &[u8], kind: KeySlotKind, secret: &[u8]) {
+    match vault.upgrade_key_slot(vault_key, kind, secret) {
+        Ok(true) => println!("upgraded {kind:?} key slot"),
+        Ok(false) => {}
+        Err(e) => eprintln!("failed to upgrade {kind:?} key slot: {e}"),
+    }
+}
+
+// like the key slot upgrade, housekeeping that must not fail the unlock
+fn purge_expired_trash(vault: &mut Vault, vault_key: &[u8]) {
+    match vault.purge_expired_trash(vault_key) {
+        Ok(0) => {}
+        Ok(count) => println!("purged {count} expired entries from the trash"),
+        Err(e) => eprintln!("failed to purge the trash: {e}"),
+    }
+}
+
+/// Answers a client's `Hello` with the agent's own, or refuses a client it
+/// shares no protocol version with.
+fn greet(hello: &Hello) -> Response {
+    let agent = Hello::current();
+    match hello.negotiate() {
+        Some(_) => Response::Hello { hello: agent },
+        None => Response::Error(ResponseError::IncompatibleProtocol {
+            agent: agent.protocol_version,
+            agent_min: agent.min_protocol_version,
+            client: hello.protocol_version,
+            client_min: hello.min_protocol_version,
+        }),
+    }
+}
+
+/// Describes the secret `request` reveals, if any, for the log of who read what.
+fn secret_read(request: &Request) -> Option<String> {
+    match request {
+        Request::GetEntry { entry } => Some(format!("entry {entry}")),
+        Request::GetOtp { entry } => Some(format!("one-time password of {entry}")),
+        // once per download rather than once per chunk
+        Request::GetAttachmentChunk {
+            entry,
+            attachment,
+            offset: 0,
+        } => Some(format!("attachment {attachment} of {entry}")),
+        _ => None,
+    }
+}
+
+async fn handle_peer_request(
+    request: Request,
+    peer: &Peer,
+    connection: u64,
+    state: Arc<Mutex<AgentState>>,
+) -> Response {
+    let secret = secret_read(&request);
+    let response = handle_request(request, connection, state).await;
+    if let Some(secret) = secret
+        && !matches!(response, Response::Error(_))
+    {
+        println!("{peer} read {secret}");
     }
+    response
 }
 
 pub async fn handle_client(
+    stream: UnixStream,
+    peer: Peer,
+    state: Arc<Mutex<AgentState>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let connection = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    let result = serve_client(stream, peer, connection, Arc::clone(&state)).await;
+    // nobody else can finish them
+    state
+        .lock()
+        .await
+        .uploads
+        .retain(|_, upload| upload.connection != connection);
+    result
+}
+
+async fn serve_client(
     mut stream: UnixStream,
+    peer: Peer,
+    connection: u64,
     state: Arc<Mutex<AgentState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buf = vec![0u8; 1024];
-    let n = stream.read(&mut buf).await?;
-    let message = String::from_utf8_lossy(&buf[..n]);
-    let request: Request = serde_json::from_str(&message)?;
-    let response = handle_request(request, state).await;
-    let response_bytes = serde_json::to_vec(&response)?;
-    stream.write_all(&response_bytes).await?;
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(mut request) = receive_message::<_, Request>(&mut stream).await? else {
+        return Ok(());
+    };
+    if let Request::Hello { hello } = &request {
+        let response = greet(hello);
+        send_message(&mut stream, &response).await?;
+        match hello.negotiate() {
+            Some(version) if version >= TAGGED_PROTOCOL_VERSION => {
+                return serve_connection(stream, peer, connection, state).await;
+            }
+            Some(_) => {}
+            None => return Ok(()),
+        }
+        // older clients send a single request after the handshake
+        match receive_message(&mut stream).await? {
+            Some(next) => request = next,
+            None => return Ok(()),
+        }
+    }
+    let response = handle_peer_request(request, &peer, connection, state).await;
+    send_message(&mut stream, &response).await?;
 
     Ok(())
 }
+
+/// Serves a connection until the client closes it. Its requests run one after
+/// another in the order they were sent, while cancellations take effect as
+/// soon as they arrive.
+async fn serve_connection(
+    stream: UnixStream,
+    peer: Peer,
+    connection: u64,
+    state: Arc<Mutex<AgentState>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut reader, mut writer) = stream.into_split();
+    let (response_tx, mut response_rx) = mpsc::unbounded_channel::<TaggedResponse>();
+    let (request_tx, mut request_rx) = mpsc::unbounded_channel::<(u64, u64, Box<Request>)>();
+    let in_flight = Arc::new(sync::Mutex::new(InFlight::default()));
+    let mut sequence: u64 = 0;
+
+    let writer = tokio::spawn(async move {
+        while let Some(response) = response_rx.recv().await {
+            send_message(&mut writer, &response).await?;
+        }
+        Ok::<_, VaultError>(())
+    });
+    let worker = {
+        let in_flight = Arc::clone(&in_flight);
+        let response_tx = response_tx.clone();
+        tokio::spawn(async move {
+            while let Some((sequence, id, request)) = request_rx.recv().await {
+                let (cancel_tx, cancel_rx) = oneshot::channel();
+                {
+                    let mut in_flight = in_flight.lock().unwrap();
+                    if in_flight.queued.get(&id) != Some(&sequence) {
+                        continue;
+                    }
+                    in_flight.queued.remove(&id);
+                    in_flight.running = Some((id, cancel_tx));
+                }
+                // a request only waits for the state lock before doing anything
+                // and then runs without yielding, so dropping it while it waits is safe
+                let response = tokio::select! {
+                    biased;
+                    response = handle_peer_request(*request, &peer, connection, Arc::clone(&state)) => response,
+                    _ = cancel_rx => Response::Error(ResponseError::Cancelled),
+                };
+                // cleared before answering, so the client may reuse the ID as soon as it has the response
+                in_flight.lock().unwrap().running = None;
+                if response_tx.send(TaggedResponse { id, response }).is_err() {
+                    break;
+                }
+            }
+        })
+    };
+
+    let received = async {
+        while let Some(message) = receive_message(&mut reader).await? {
+            match message {
+                ClientMessage::Request { id, request } => {
+                    if in_flight.lock().unwrap().contains(id) {
+                        let response = Response::Error(ResponseError::Other(format!(
+                            "request ID {id} is already in use"
+                        )));
+                        let _ = response_tx.send(TaggedResponse { id, response });
+                        continue;
+                    }
+                    sequence += 1;
+                    in_flight.lock().unwrap().queued.insert(id, sequence);
+                    let _ = request_tx.send((sequence, id, request));
+                }
+                ClientMessage::Cancel { id } => {
+                    let mut in_flight = in_flight.lock().unwrap();
+                    if in_flight.queued.remove(&id).is_some() {
+                        let response = Response::Error(ResponseError::Cancelled);
+                        let _ = response_tx.send(TaggedResponse { id, response });
+                    } else if in_flight
+                        .running
+                        .as_ref()
+                        .is_some_and(|(running, _)| *running == id)
+                    {
+                        // answered by the worker, unless the request already finished
+                        let (_, cancel_tx) = in_flight.running.take().unwrap();
+                        let _ = cancel_tx.send(());
+                    }
+                }
+            }
+        }
+        Ok::<_, VaultError>(())
+    }
+    .await;
+
+    // let what's queued finish and be answered before closing, even after a
+    // read error, so nothing runs once the connection is cleaned up
+    drop(request_tx);
+    drop(response_tx);
+    worker.await?;
+    writer.await??;
+    received?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn send(stream: &mut UnixStream, message: ClientMessage) {
+        send_message(stream, &message).await.unwrap();
+    }
+
+    async fn receive(stream: &mut UnixStream) -> TaggedResponse {
+        receive_message(stream).await.unwrap().unwrap()
+    }
+
+    fn serve(
+        state: &Arc<Mutex<AgentState>>,
+    ) -> (UnixStream, tokio::task::JoinHandle<Result<(), String>>) {
+        let (client, agent) = UnixStream::pair().unwrap();
+        let peer = Peer {
+            uid: 0,
+            pid: None,
+            exe: None,
+        };
+        let server = tokio::spawn({
+            let state = Arc::clone(state);
+            async move {
+                serve_connection(agent, peer, 0, state)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        });
+        (client, server)
+    }
+
+    fn hello() -> Box<Request> {
+        Box::new(Request::Hello {
+            hello: Hello::current(),
+        })
+    }
+
+    #[tokio::test]
+    async fn reused_id_after_cancel_answers_the_new_request() {
+        let state = Arc::new(Mutex::new(AgentState::new()));
+        let (mut client, server) = serve(&state);
+
+        // keeps the worker stuck on the first request while the rest queue up
+        let busy = state.lock().await;
+        send(
+            &mut client,
+            ClientMessage::Request {
+                id: 1,
+                request: hello(),
+            },
+        )
+        .await;
+        send(
+            &mut client,
+            ClientMessage::Request {
+                id: 2,
+                request: Box::new(Request::LockVault),
+            },
+        )
+        .await;
+        send(&mut client, ClientMessage::Cancel { id: 2 }).await;
+        let cancelled = receive(&mut client).await;
+        assert_eq!(cancelled.id, 2);
+        assert!(matches!(
+            cancelled.response,
+            Response::Error(ResponseError::Cancelled)
+        ));
+        // the second is rejected right away, which shows the first has been queued
+        for _ in 0..2 {
+            send(
+                &mut client,
+                ClientMessage::Request {
+                    id: 2,
+                    request: hello(),
+                },
+            )
+            .await;
+        }
+        let duplicate = receive(&mut client).await;
+        assert_eq!(duplicate.id, 2);
+        assert!(matches!(
+            duplicate.response,
+            Response::Error(ResponseError::Other(_))
+        ));
+        drop(busy);
+
+        for id in [1, 2] {
+            let answered = receive(&mut client).await;
+            assert_eq!(answered.id, id);
+            assert!(matches!(answered.response, Response::Hello { .. }));
+        }
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_only_take_chunks_from_their_connection() {
+        let state = Arc::new(Mutex::new(AgentState::new()));
+        let upload = Uuid::new_v4();
+        state.lock().await.uploads.insert(
+            upload,
+            PendingUpload {
+                connection: 1,
+                entry: "github".to_string(),
+                name: "codes.txt".to_string(),
+                size: 4,
+                data: Zeroizing::new(Vec::new()),
+            },
+        );
+        let chunk = || Request::UploadAttachmentChunk {
+            upload,
+            data: b"1234".to_vec(),
+        };
+
+        let locked = handle_request(chunk(), 1, Arc::clone(&state)).await;
+        assert!(matches!(locked, Response::Error(ResponseError::Locked)));
+
+        state.lock().await.vault_key = Some(Zeroizing::new(vec![0; 32]));
+        let foreign = handle_request(chunk(), 2, Arc::clone(&state)).await;
+        assert!(matches!(foreign, Response::Error(ResponseError::Other(_))));
+        let finish = Request::FinishAttachmentUpload { upload };
+        let foreign = handle_request(finish, 2, Arc::clone(&state)).await;
+        assert!(matches!(foreign, Response::Error(ResponseError::Other(_))));
+
+        let own = handle_request(chunk(), 1, Arc::clone(&state)).await;
+        assert!(matches!(own, Response::Ok));
+        assert_eq!(*state.lock().await.uploads[&upload].data, b"1234");
+    }
+
+    #[tokio::test]
+    async fn running_request_keeps_its_id_and_can_be_cancelled() {
+        let state = Arc::new(Mutex::new(AgentState::new()));
+        let (mut client, server) = serve(&state);
+
+        let busy = state.lock().await;
+        send(
+            &mut client,
+            ClientMessage::Request {
+                id: 1,
+                request: hello(),
+            },
+        )
+        .await;
+        // once this is answered the worker has taken the first request and
+        // is waiting for the state lock with it
+        send(
+            &mut client,
+            ClientMessage::Request {
+                id: 2,
+                request: hello(),
+            },
+        )
+        .await;
+        send(&mut client, ClientMessage::Cancel { id: 2 }).await;
+        assert_eq!(receive(&mut client).await.id, 2);
+
+        send(
+            &mut client,
+            ClientMessage::Request {
+                id: 1,
+                request: hello(),
+            },
+        )
+        .await;
+        let duplicate = receive(&mut client).await;
+        assert_eq!(duplicate.id, 1);
+        assert!(matches!(
+            duplicate.response,
+            Response::Error(ResponseError::Other(_))
+        ));
+
+        send(&mut client, ClientMessage::Cancel { id: 1 }).await;
+        let cancelled = receive(&mut client).await;
+        assert_eq!(cancelled.id, 1);
+        assert!(matches!(
+            cancelled.response,
+            Response::Error(ResponseError::Cancelled)
+        ));
+        drop(busy);
+
+        // the cancelled request never answers, the next one is next
+        send(
+            &mut client,
+            ClientMessage::Request {
+                id: 3,
+                request: hello(),
+            },
+        )
+        .await;
+        let answered = receive(&mut client).await;
+        assert_eq!(answered.id, 3);
+        assert!(matches!(answered.response, Response::Hello { .. }));
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -1,22 +1,53 @@
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::process::exit;
 use std::sync::Arc;
-use tokio::{net::UnixListener, sync::Mutex};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    signal::unix::{SignalKind, signal},
+    sync::Mutex,
+};
+use vpassword_core::{
+    errors::ResponseError,
+    models::Response,
+    protocol::send_message,
+    socket::{AgentSocket, current_uid},
+};
 
 mod agent;
 mod handlers;
 mod models;
+mod peer;
 use handlers::handle_client;
 use models::AgentState;
+use peer::{allowed_clients, verify_peer};
 
 // TODO: we need to be sending stuff back (results, errors, etc,)
-// FIX: clean up of sock file after exiting
 #[tokio::main]
 async fn main() {
-    let socket_path = "/tmp/vault.sock";
+    let (socket, uid) = match AgentSocket::locate().and_then(|socket| {
+        let uid = current_uid()?;
+        socket.prepare(uid)?;
+        Ok((socket, uid))
+    }) {
+        Ok(located) => located,
+        Err(e) => {
+            eprintln!("Error setting up the agent socket: {e}");
+            exit(1);
+        }
+    };
+    let socket_path = &socket.path;
 
-    if Path::new(socket_path).exists() {
+    if let Ok(existing) = fs::symlink_metadata(socket_path) {
+        if UnixStream::connect(socket_path).await.is_ok() {
+            eprintln!("An agent is already listening on {}", socket_path.display());
+            exit(1);
+        }
+        if !existing.file_type().is_socket() {
+            eprintln!("{} exists and is not a socket", socket_path.display());
+            exit(1);
+        }
+        // left behind by an agent that didn't get to clean up
         let _ = fs::remove_file(socket_path);
     }
 
@@ -26,16 +57,44 @@ async fn main() {
     let mut permissions = metadata.permissions();
     permissions.set_mode(0o600);
     fs::set_permissions(socket_path, permissions).unwrap();
+    let allowed = allowed_clients();
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
 
     loop {
         let clone_for_task = Arc::clone(&state);
-        match listener.accept().await {
-            Ok((stream, _addr)) => {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+            _ = hangup.recv() => break,
+        };
+        match accepted {
+            Ok((mut stream, _addr)) => {
+                // the permissions are set after bind, so don't rely on them alone
+                let peer = match verify_peer(&stream, uid, &allowed) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        eprintln!("rejected client: {e}");
+                        tokio::spawn(async move {
+                            let response = Response::Error(ResponseError::PermissionDenied(e));
+                            let _ = send_message(&mut stream, &response).await;
+                        });
+                        continue;
+                    }
+                };
                 tokio::spawn(async move {
-                    handle_client(stream, clone_for_task).await.unwrap();
+                    if let Err(e) = handle_client(stream, peer, clone_for_task).await {
+                        eprintln!("client error: {e}");
+                    }
                 });
             }
             Err(e) => eprintln!("accept failed: {e}"),
         }
     }
+
+    // another agent may have taken over the path since, leave its socket alone
+    if fs::metadata(socket_path).is_ok_and(|current| current.ino() == metadata.ino()) {
+        let _ = fs::remove_file(socket_path);
+    }
 }
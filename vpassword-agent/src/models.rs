@@ -1,9 +1,21 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 use tokio::time::Instant;
+use uuid::Uuid;
 use zeroize::Zeroizing;
 
 pub struct AgentState {
     pub vault_key: Option<Zeroizing<Vec<u8>>>,
     pub vault_path: Option<PathBuf>,
     pub last_activity: Option<Instant>,
+    pub uploads: HashMap<Uuid, PendingUpload>,
+}
+
+/// An attachment whose chunks are still arriving.
+pub struct PendingUpload {
+    /// The connection that started it, the upload is dropped when it closes.
+    pub connection: u64,
+    pub entry: String,
+    pub name: String,
+    pub size: u64,
+    pub data: Zeroizing<Vec<u8>>,
 }
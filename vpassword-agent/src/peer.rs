@@ -0,0 +1,60 @@
+use std::{env, fmt, fs, path::PathBuf};
+
+use tokio::net::UnixStream;
+
+/// Colon separated executables that may use the agent, like `PATH`. Unset or
+/// empty lets any program running as the agent's user in.
+pub const ALLOWED_CLIENTS_VAR: &str = "VPASSWORD_AGENT_ALLOWED_CLIENTS";
+
+/// The process on the other end of a connection, as the kernel reports it.
+pub struct Peer {
+    pub uid: u32,
+    pub pid: Option<i32>,
+    pub exe: Option<PathBuf>,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "pid {pid}")?,
+            None => write!(f, "unknown pid")?,
+        }
+        match &self.exe {
+            Some(exe) => write!(f, " ({})", exe.display()),
+            None => write!(f, " (unknown executable)"),
+        }
+    }
+}
+
+pub fn allowed_clients() -> Vec<PathBuf> {
+    let Some(paths) = env::var_os(ALLOWED_CLIENTS_VAR) else {
+        return Vec::new();
+    };
+    env::split_paths(&paths)
+        .filter(|path| !path.as_os_str().is_empty())
+        // `/proc/<pid>/exe` has every symlink resolved
+        .map(|path| fs::canonicalize(&path).unwrap_or(path))
+        .collect()
+}
+
+/// Identifies the client on `stream` and checks that it runs as `uid` and,
+/// if there is an allowlist, is one of the executables on it.
+pub fn verify_peer(stream: &UnixStream, uid: u32, allowed: &[PathBuf]) -> Result<Peer, String> {
+    let credentials = stream
+        .peer_cred()
+        .map_err(|e| format!("can't identify client: {e}"))?;
+    let pid = credentials.pid();
+    let peer = Peer {
+        uid: credentials.uid(),
+        pid,
+        exe: pid.and_then(|pid| fs::read_link(format!("/proc/{pid}/exe")).ok()),
+    };
+
+    if peer.uid != uid {
+        return Err(format!("{peer} runs as uid {}", peer.uid));
+    }
+    if !allowed.is_empty() && !peer.exe.as_ref().is_some_and(|exe| allowed.contains(exe)) {
+        return Err(format!("{peer} is not an allowed client"));
+    }
+    Ok(peer)
+}
@@ -1,5 +1,5 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Parser)]
 #[command(name = "password", about = "A simple password manager CLI")]
@@ -10,14 +10,293 @@ pub struct Cli {
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    Init { vault_path: PathBuf },
-    Open { vault_path: PathBuf },
+    Init {
+        vault_path: PathBuf,
+        /// Calibrate Argon2 so unlocking takes about this long on this machine (e.g. 1s, 500ms)
+        #[arg(long, value_parser = parse_duration)]
+        kdf_target: Option<Duration>,
+        #[arg(long, value_enum, default_value_t = CipherArg::Aes256Gcm)]
+        cipher: CipherArg,
+    },
+    Open {
+        vault_path: PathBuf,
+        /// Unlock with a keyfile slot instead of the master password
+        #[arg(long, conflicts_with = "recovery")]
+        keyfile: Option<PathBuf>,
+        /// Unlock with the recovery key
+        #[arg(long)]
+        recovery: bool,
+    },
     Close,
-    Generate { name: String, username: String },
-    Add { name: String, username: String },
-    Show { name: String },
+    Passwd,
+    Keyslot {
+        #[command(subcommand)]
+        action: KeyslotAction,
+    },
+    /// NAME can be a path such as work/aws/console to put the entry in a folder
+    Generate {
+        name: String,
+        username: String,
+        #[command(flatten)]
+        details: EntryDetails,
+    },
+    /// NAME can be a path such as work/aws/console to put the entry in a folder
+    Add {
+        name: String,
+        username: String,
+        #[command(flatten)]
+        details: EntryDetails,
+    },
+    /// Change fields of an existing entry
+    Edit {
+        name: String,
+        #[arg(long)]
+        username: Option<String>,
+        /// Prompt for a new password
+        #[arg(long)]
+        password: bool,
+        #[command(flatten)]
+        details: EntryDetails,
+        /// Name of a custom field to remove, can be given multiple times
+        #[arg(long = "remove-field")]
+        removed_fields: Vec<String>,
+        /// Remove all URLs, --url replaces them instead
+        #[arg(long, conflicts_with = "urls")]
+        clear_urls: bool,
+        /// Remove all tags, --tag replaces them instead
+        #[arg(long, conflicts_with = "tags")]
+        clear_tags: bool,
+        /// Prompt for every field, showing the current values
+        #[arg(short, long)]
+        interactive: bool,
+    },
+    Show {
+        name: String,
+    },
+    /// Show previous passwords of an entry, most recent first
+    History {
+        name: String,
+        /// Make the password at this position in the history current again
+        #[arg(long, value_name = "NUMBER")]
+        restore: Option<usize>,
+    },
     List,
-    Remove { name: String },
+    /// Print the current one-time password of an entry
+    Otp {
+        name: String,
+    },
+    /// Store a file encrypted alongside an entry
+    Attach {
+        name: String,
+        file: PathBuf,
+        /// Name to store the file under, defaults to its file name
+        #[arg(long = "as")]
+        attachment_name: Option<String>,
+    },
+    /// Decrypt an attachment of an entry into a file
+    Extract {
+        name: String,
+        attachment: String,
+        /// Where to write the file, defaults to the attachment name
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Delete an attachment from an entry
+    Detach {
+        name: String,
+        attachment: String,
+    },
+    /// Find entries by name, path, username, URL or tag, best match first
+    Search {
+        #[arg(default_value = "")]
+        query: String,
+        /// Only entries with this tag, can be given multiple times
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        #[arg(long)]
+        url: Option<String>,
+        #[arg(long)]
+        username: Option<String>,
+        /// Only entries in this folder or below it, / searches the whole tree
+        #[arg(long)]
+        folder: Option<String>,
+        /// Also match names containing the query's characters in order
+        #[arg(short, long)]
+        fuzzy: bool,
+    },
+    /// Rename an entry, keeping its ID, history and other fields
+    Rename {
+        name: String,
+        new_name: String,
+    },
+    /// Move an entry into another folder, use / for the top level
+    Move {
+        name: String,
+        folder: String,
+    },
+    Folder {
+        #[command(subcommand)]
+        action: FolderAction,
+    },
+    /// Move an entry to the trash
+    Remove {
+        name: String,
+    },
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+    Backup {
+        #[command(subcommand)]
+        action: BackupAction,
+    },
+    /// Check a vault file for damage without opening it in the agent
+    Check {
+        vault_path: PathBuf,
+        /// Replace a damaged vault with its newest backup that passes the check
+        #[arg(long)]
+        repair: bool,
+        /// Where the backups are, if the vault is too damaged to tell
+        #[arg(long)]
+        backup_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct EntryDetails {
+    /// URL of the site or service, can be given multiple times
+    #[arg(long = "url")]
+    pub urls: Vec<String>,
+    #[arg(long)]
+    pub notes: Option<String>,
+    /// Can be given multiple times
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+    /// Custom field as NAME=VALUE, can be given multiple times
+    #[arg(long = "field", value_parser = parse_field)]
+    pub fields: Vec<(String, String)>,
+    /// Name of a sensitive custom field whose value is prompted for, can be given multiple times
+    #[arg(long = "secret-field")]
+    pub secret_fields: Vec<String>,
+    /// Prompt for an otpauth:// URI (TOTP or HOTP) to generate one-time passwords from
+    #[arg(long)]
+    pub otp: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyslotAction {
+    /// Allow unlocking the open vault with the given file
+    AddKeyfile {
+        keyfile: PathBuf,
+    },
+    /// Generate a recovery key that can unlock the open vault
+    AddRecovery,
+    Remove {
+        kind: KeySlotArg,
+    },
+    /// Set the weakest Argon2 parameters the open vault accepts, weaker key
+    /// slots are strengthened the next time they unlock it
+    Policy {
+        /// Calibrate the minimum so unlocking takes about this long on this machine
+        #[arg(long, value_parser = parse_duration, conflicts_with_all = ["min_mem_cost", "min_time_cost"])]
+        kdf_target: Option<Duration>,
+        /// Memory in KiB, defaults to 65536
+        #[arg(long)]
+        min_mem_cost: Option<u32>,
+        /// Iterations, defaults to 3
+        #[arg(long)]
+        min_time_cost: Option<u32>,
+        /// Lanes, defaults to 1
+        #[arg(long)]
+        min_parallelism: Option<u32>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FolderAction {
+    Create {
+        path: String,
+    },
+    /// Move or rename a folder together with everything in it
+    Move {
+        from: String,
+        to: String,
+    },
+    /// Delete an empty folder
+    Delete {
+        path: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BackupAction {
+    /// List the automatic backups of the open vault, newest first
+    List,
+    /// Replace the open vault with a backup, the current state is backed up first
+    Restore { name: String },
+    /// Set how many backups to keep (0 turns them off) and where
+    Config {
+        keep: usize,
+        /// Defaults to a <vault>.backups directory next to the vault
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TrashAction {
+    List,
+    /// Put a removed entry back where it was
+    Restore {
+        name: String,
+    },
+    /// Delete an entry for good, or everything in the trash when no name is given
+    Purge {
+        name: Option<String>,
+    },
+    /// Purge removed entries automatically after this many days
+    Retention {
+        days: u32,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum KeySlotArg {
+    Keyfile,
+    Recovery,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum CipherArg {
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[value(name = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+fn parse_field(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got: {value}"))
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration: {value}"))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(format!("unknown duration unit: {unit}")),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
 }
 
 pub fn parse_cli() -> Commands {
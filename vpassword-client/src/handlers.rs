@@ -1,78 +1,317 @@
-use crate::cli::Commands;
+use crate::cli::{
+    BackupAction, CipherArg, Commands, EntryDetails, FolderAction, KeySlotArg, KeyslotAction,
+    TrashAction,
+};
 use passwords::PasswordGenerator;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
 use std::process::Command;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::UnixStream,
-    time::Duration,
+use tokio::{net::UnixStream, time::Duration};
+use vpassword_core::{
+    attachment::ATTACHMENT_TRANSFER_CHUNK,
+    backup::BackupPolicy,
+    check::{Diagnosis, Outcome, check_vault, repair_from_backup},
+    cipher::CipherKind,
+    errors::ResponseError,
+    folder::{normalize_folder, split_path},
+    kdf::KdfPolicy,
+    keyslot::generate_recovery_key,
+    models::{
+        Argon2Params, CustomField, EntrySummary, EntryUpdate, KeySlotKind, PasswordEntry, Request,
+        Response, SearchFilters, Vault,
+    },
+    protocol::{
+        Connection, Hello, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, TAGGED_PROTOCOL_VERSION,
+        receive_message, send_message,
+    },
+    socket::{AgentSocket, current_uid},
 };
-use vpassword_core::models::{PasswordEntry, PasswordList, Request, Response, Vault};
+
+/// How often `open` asks again after a wrong password or recovery key.
+const UNLOCK_ATTEMPTS: usize = 3;
 
 pub async fn handle_command(command: Commands) {
     match command {
-        Commands::Init { vault_path } => {
-            handle_init(vault_path);
+        Commands::Init {
+            vault_path,
+            kdf_target,
+            cipher,
+        } => {
+            handle_init(vault_path, kdf_target, cipher);
+        }
+        Commands::Check {
+            vault_path,
+            repair,
+            backup_dir,
+        } => {
+            handle_check(vault_path, repair, backup_dir);
         }
         _ => {
-            let stream: UnixStream = match UnixStream::connect("/tmp/vault.sock").await {
-                Ok(s) => s,
-                Err(_) => {
-                    println!("Agent not found. Attempting to start it..");
-
-                    match Command::new("vpassword-agent").spawn() {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Error trying to start agent: {e}");
-                            std::process::exit(1);
-                        }
-                    };
-                    let mut attempts = 0;
-
-                    loop {
-                        if attempts >= 5 {
-                            eprintln!("Error trying to start agent");
-                            std::process::exit(1);
-                        }
-                        tokio::time::sleep(Duration::from_millis(500)).await;
-                        let stream = match UnixStream::connect("/tmp/vault.sock").await {
-                            Ok(s) => s,
-                            Err(_) => {
-                                attempts += 1;
-                                continue;
-                            }
-                        };
-                        break stream;
-                    }
-                }
-            };
+            let connection = connect_to_agent().await;
             println!("Coneected to agent!");
+            handle_agent_command(command, connection).await;
+        }
+    }
+}
+
+type AgentConnection = Connection<UnixStream>;
+
+async fn connect_to_agent() -> AgentConnection {
+    let socket = match AgentSocket::locate() {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Error finding the agent socket: {e}");
+            std::process::exit(1);
+        }
+    };
+    let mut stream: UnixStream = match connect(&socket).await {
+        Some(s) => s,
+        None => {
+            println!("Agent not found. Attempting to start it..");
+            spawn_agent(&socket).await
+        }
+    };
+    match handshake(&mut stream).await {
+        Ok(_) => Connection::new(stream),
+        Err(reason) => restart_outdated_agent(&socket, &reason).await,
+    }
+}
+
+/// Connects to the agent listening on `socket`, refusing to talk to anything
+/// another user could have put there, as it would be handed our secrets.
+async fn connect(socket: &AgentSocket) -> Option<UnixStream> {
+    let stream = UnixStream::connect(&socket.path).await.ok()?;
+    let verified = current_uid().and_then(|uid| {
+        socket.check(uid)?;
+        let agent_uid = stream.peer_cred()?.uid();
+        Ok(agent_uid == uid)
+    });
+    match verified {
+        Ok(true) => Some(stream),
+        Ok(false) => {
+            eprintln!(
+                "Refusing to use the agent on {}: it runs as another user",
+                socket.path.display()
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!(
+                "Refusing to use the agent on {}: {e}",
+                socket.path.display()
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn spawn_agent(socket: &AgentSocket) -> UnixStream {
+    // the agent finds the same socket, `VPASSWORD_AGENT_SOCK` is inherited
+    let pid = match Command::new("vpassword-agent").spawn() {
+        Ok(child) => child.id(),
+        Err(e) => {
+            eprintln!("Error trying to start agent: {e}");
+            std::process::exit(1);
+        }
+    };
+    let mut attempts = 0;
+
+    let stream = loop {
+        if attempts >= 5 {
+            eprintln!("Error trying to start agent");
+            std::process::exit(1);
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let stream = match connect(socket).await {
+            Some(s) => s,
+            None => {
+                attempts += 1;
+                continue;
+            }
+        };
+        break stream;
+    };
+    // note which agent we started, so we know we may restart it; the socket
+    // directory exists once the agent listens
+    let _ = std::fs::write(socket.pid_file(), pid.to_string());
+    stream
+}
 
-            handle_agent_command(command, stream).await;
+/// Exchanges `Hello`s with the agent, returning the agent's if both sides
+/// share a protocol version and why not otherwise.
+async fn handshake(stream: &mut UnixStream) -> Result<Hello, String> {
+    let request = Request::Hello {
+        hello: Hello::current(),
+    };
+    // an agent refusing the connection answers before reading anything, so
+    // read its answer even if it has already closed the connection
+    let sent = send_message(stream, &request).await;
+    match receive_message::<_, Response>(stream).await {
+        Ok(Some(Response::Hello { hello })) => match hello.negotiate() {
+            Some(version) if version >= TAGGED_PROTOCOL_VERSION => Ok(hello),
+            _ => Err(format!(
+                "the agent ({}) speaks protocol versions {} to {}, this client {} to {}",
+                hello.software_version,
+                hello.min_protocol_version,
+                hello.protocol_version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            )),
+        },
+        Ok(Some(Response::Error(ResponseError::PermissionDenied(reason)))) => {
+            eprintln!("The agent refused the connection: {reason}");
+            std::process::exit(1);
         }
+        Ok(Some(Response::Error(e))) => Err(e.to_string()),
+        _ => match sent {
+            Err(e) => Err(e.to_string()),
+            // agents from before the handshake drop the connection on a request they can't read
+            Ok(()) => Err("the agent does not understand the protocol handshake".to_string()),
+        },
     }
 }
-pub fn handle_init(vault_path: std::path::PathBuf) {
+
+/// The PID of the agent this client started earlier, if that agent is still running.
+fn spawned_agent_pid(socket: &AgentSocket) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(socket.pid_file())
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    // the PID may have been reused by something else since
+    let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    (comm.trim() == "vpassword-agent").then_some(pid)
+}
+
+async fn restart_outdated_agent(socket: &AgentSocket, reason: &str) -> AgentConnection {
+    eprintln!("The running agent can't be used with this client: {reason}");
+    let Some(pid) = spawned_agent_pid(socket) else {
+        eprintln!("It was not started by vpassword, restart it yourself to continue.");
+        std::process::exit(1);
+    };
+    print!(
+        "It was started by vpassword earlier. Restart it? Any open vault will be locked. [y/N] "
+    );
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        std::process::exit(1);
+    }
+
+    if let Err(e) = Command::new("kill").arg(pid.to_string()).status() {
+        eprintln!("Error trying to stop agent: {e}");
+        std::process::exit(1);
+    }
+    for _ in 0..10 {
+        if !std::path::Path::new(&format!("/proc/{pid}")).exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    let mut stream = spawn_agent(socket).await;
+    match handshake(&mut stream).await {
+        Ok(_) => Connection::new(stream),
+        Err(reason) => {
+            eprintln!("The installed agent can't be used with this client either: {reason}");
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn handle_init(
+    vault_path: std::path::PathBuf,
+    kdf_target: Option<Duration>,
+    cipher: CipherArg,
+) {
     let mut vault = Vault::new(&vault_path);
+    vault.encryption.cipher = match cipher {
+        CipherArg::Aes256Gcm => CipherKind::Aes256Gcm,
+        CipherArg::XChaCha20Poly1305 => CipherKind::XChaCha20Poly1305,
+    };
+    let argon2 = match kdf_target {
+        Some(target) => {
+            println!("Calibrating key derivation for {target:?}..");
+            let argon2 = Argon2Params::calibrate(target).expect("Error calibrating Argon2");
+            println!(
+                "Using Argon2id with {} KiB memory and {} iterations",
+                argon2.mem_cost, argon2.time_cost
+            );
+            argon2
+        }
+        None => Argon2Params::default(),
+    };
     let master_password = rpassword::prompt_password("Your master password: ").unwrap();
-    let vault_key = vault
-        .derive_vault_key(master_password.as_ref())
-        .expect("Error trying to encrypt master key");
     vault
-        .encrypt_data(
-            &vault_key,
-            serde_json::to_string(&PasswordList::default())
-                .unwrap()
-                .as_bytes(),
-        )
-        .expect("Error encrypting data");
-    vault.save_to_file().expect("Error saving to file");
+        .initialize(master_password.as_ref(), argon2)
+        .expect("Error initializing vault");
     println!("Vault initialized at {:?}", vault_path);
 }
 
-pub async fn handle_agent_command(command: Commands, stream: UnixStream) {
+pub fn handle_check(
+    vault_path: std::path::PathBuf,
+    repair: bool,
+    backup_dir: Option<std::path::PathBuf>,
+) {
+    let master_password =
+        rpassword::prompt_password("Your master password (enter skips the decryption checks): ")
+            .unwrap();
+    let master_password = (!master_password.is_empty()).then_some(master_password);
+    let report = check_vault(&vault_path, master_password.as_deref().map(str::as_bytes));
+    for check in &report.checks {
+        match &check.outcome {
+            Outcome::Passed => println!("[ok]     {}", check.name),
+            Outcome::Warning(reason) => println!("[warn]   {}: {reason}", check.name),
+            Outcome::Failed(reason) => println!("[FAILED] {}: {reason}", check.name),
+            Outcome::Skipped(reason) => println!("[skip]   {}: {reason}", check.name),
+        }
+    }
+    match report.diagnosis {
+        Diagnosis::Healthy => println!("The vault is healthy."),
+        Diagnosis::Unverified => {
+            println!("The vault file looks intact, its contents were not checked.")
+        }
+        Diagnosis::WrongPassword => {
+            println!("The vault file is intact, but the password is wrong.")
+        }
+        Diagnosis::Corrupted => println!("The vault is damaged."),
+    }
+
+    if !repair {
+        return;
+    }
+    match (report.diagnosis, master_password) {
+        (Diagnosis::Healthy, _) => println!("Nothing to repair."),
+        (Diagnosis::WrongPassword, _) => {
+            eprintln!("Not repairing, the vault is intact and only the password is wrong.")
+        }
+        (_, None) => eprintln!("The master password is needed to find a good backup."),
+        (_, Some(master_password)) => {
+            match repair_from_backup(
+                &vault_path,
+                backup_dir.as_deref(),
+                master_password.as_bytes(),
+            ) {
+                Ok(backup) => println!(
+                    "Restored {} ({}), the damaged vault was kept next to it as .corrupt",
+                    backup.name,
+                    format_timestamp(backup.created_at / 1000)
+                ),
+                Err(e) => eprintln!("Error: {e}"),
+            }
+        }
+    }
+}
+
+pub async fn handle_agent_command(command: Commands, mut connection: AgentConnection) {
     match command {
-        Commands::Init { vault_path: _ } => {}
-        Commands::Open { vault_path } => {
+        Commands::Init { .. } | Commands::Check { .. } => {}
+        Commands::Open {
+            vault_path,
+            keyfile,
+            recovery,
+        } => {
             // NOTE: check if vault exists
             // check if a vault is already open
             // check if that vault is this vault
@@ -80,27 +319,140 @@ pub async fn handle_agent_command(command: Commands, stream: UnixStream) {
             // if not prompt for master password
             // send open request to agent
             // send appropriate message based on if open or not
-            let master_password = rpassword::prompt_password("Your master password: ").unwrap();
-            let response = send_request_to_agent(
-                stream,
-                Request::UnlockVault {
-                    vault_path,
-                    master_password: master_password.into_bytes(),
-                },
-            )
-            .await;
-            match response {
-                Response::Ok => println!("Vault is Opened!"),
-                Response::Error(e) => println!("Problem Openning Vault: {e}"),
-                _ => eprintln!("Unexpected response type."),
-            };
+            for attempt in 1..=UNLOCK_ATTEMPTS {
+                let request = if let Some(keyfile) = &keyfile {
+                    Request::UnlockVaultWithKeySlot {
+                        vault_path: vault_path.clone(),
+                        kind: KeySlotKind::Keyfile,
+                        secret: std::fs::read(keyfile).expect("Error reading keyfile"),
+                    }
+                } else if recovery {
+                    let recovery_key = rpassword::prompt_password("Your recovery key: ").unwrap();
+                    Request::UnlockVaultWithKeySlot {
+                        vault_path: vault_path.clone(),
+                        kind: KeySlotKind::RecoveryKey,
+                        secret: recovery_key.trim().as_bytes().to_vec(),
+                    }
+                } else {
+                    let master_password =
+                        rpassword::prompt_password("Your master password: ").unwrap();
+                    Request::UnlockVault {
+                        vault_path: vault_path.clone(),
+                        master_password: master_password.into_bytes(),
+                    }
+                };
+                match send_request_to_agent(&mut connection, request).await {
+                    Response::Ok => return println!("Vault is Opened!"),
+                    // a keyfile won't get any better by trying again
+                    Response::Error(ResponseError::WrongPassword)
+                        if keyfile.is_none() && attempt < UNLOCK_ATTEMPTS =>
+                    {
+                        eprintln!("Wrong password, try again.")
+                    }
+                    Response::Error(e @ ResponseError::Corrupted(_)) => {
+                        eprintln!("Problem Openning Vault: {e}");
+                        return eprintln!(
+                            "Run `vpassword check {} --repair` to restore it from a backup.",
+                            vault_path.display()
+                        );
+                    }
+                    Response::Error(e) => return println!("Problem Openning Vault: {e}"),
+                    _ => return eprintln!("Unexpected response type."),
+                }
+            }
         }
-        Commands::Close => match send_request_to_agent(stream, Request::LockVault).await {
+        Commands::Close => match send_request_to_agent(&mut connection, Request::LockVault).await {
             Response::Ok => println!("Vault sucessfully closed!"),
             Response::Error(e) => println!("Problem closing Vault: {e}"),
             _ => eprintln!("Unexpected response type."),
         },
-        Commands::Generate { name, username } => {
+        Commands::Passwd => {
+            let old_password = rpassword::prompt_password("Current master password: ").unwrap();
+            let new_password = rpassword::prompt_password("New master password: ").unwrap();
+            let confirmation = rpassword::prompt_password("Repeat new master password: ").unwrap();
+            if new_password != confirmation {
+                eprintln!("Passwords do not match.");
+                return;
+            }
+            match send_request_to_agent(
+                &mut connection,
+                Request::ChangeMasterPassword {
+                    old_password: old_password.into_bytes(),
+                    new_password: new_password.into_bytes(),
+                },
+            )
+            .await
+            {
+                Response::Ok => println!("Master password changed!"),
+                Response::Error(e) => println!("Problem changing master password: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Keyslot { action } => {
+            let (request, recovery_key) = match action {
+                KeyslotAction::AddKeyfile { keyfile } => (
+                    Request::AddKeySlot {
+                        kind: KeySlotKind::Keyfile,
+                        secret: std::fs::read(keyfile).expect("Error reading keyfile"),
+                    },
+                    None,
+                ),
+                KeyslotAction::AddRecovery => {
+                    let recovery_key = generate_recovery_key();
+                    (
+                        Request::AddKeySlot {
+                            kind: KeySlotKind::RecoveryKey,
+                            secret: recovery_key.as_bytes().to_vec(),
+                        },
+                        Some(recovery_key),
+                    )
+                }
+                KeyslotAction::Remove { kind } => (
+                    Request::RemoveKeySlot {
+                        kind: match kind {
+                            KeySlotArg::Keyfile => KeySlotKind::Keyfile,
+                            KeySlotArg::Recovery => KeySlotKind::RecoveryKey,
+                        },
+                    },
+                    None,
+                ),
+                KeyslotAction::Policy {
+                    kdf_target,
+                    min_mem_cost,
+                    min_time_cost,
+                    min_parallelism,
+                } => {
+                    let argon2 = match kdf_target {
+                        Some(target) => {
+                            println!("Calibrating key derivation for {target:?}..");
+                            Argon2Params::calibrate(target).expect("Error calibrating Argon2")
+                        }
+                        None => Argon2Params::default(),
+                    };
+                    let policy = KdfPolicy {
+                        min_mem_cost: min_mem_cost.unwrap_or(argon2.mem_cost),
+                        min_time_cost: min_time_cost.unwrap_or(argon2.time_cost),
+                        min_parallelism: min_parallelism.unwrap_or(argon2.parallelism),
+                    };
+                    (Request::SetKdfPolicy { policy }, None)
+                }
+            };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::Ok => match recovery_key {
+                    Some(recovery_key) => println!(
+                        "Recovery key added. Store it somewhere safe, it will not be shown again:\n{recovery_key}"
+                    ),
+                    None => println!("Key slots updated!"),
+                },
+                Response::Error(e) => println!("Problem updating key slots: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Generate {
+            name,
+            username,
+            details,
+        } => {
             let pg = PasswordGenerator {
                 length: 15,
                 numbers: true,
@@ -112,9 +464,12 @@ pub async fn handle_agent_command(command: Commands, stream: UnixStream) {
                 strict: true,
             };
             let user_password = pg.generate_one().expect("Error generating password");
-            let password_entry = PasswordEntry::new(&name, &username, &user_password);
+            let (folder, entry_name) = split_path(&name);
+            let mut password_entry = PasswordEntry::new(&entry_name, &username, &user_password);
+            password_entry.folder = folder;
+            apply_entry_details(&mut password_entry, details);
             match send_request_to_agent(
-                stream,
+                &mut connection,
                 Request::AddEntry {
                     entry: password_entry,
                 },
@@ -128,44 +483,384 @@ pub async fn handle_agent_command(command: Commands, stream: UnixStream) {
                 _ => eprintln!("Unexpected response type."),
             }
         }
+        Commands::Edit {
+            name,
+            username,
+            password,
+            details,
+            removed_fields,
+            clear_urls,
+            clear_tags,
+            interactive,
+        } => {
+            let update = if interactive {
+                let entry = match send_request_to_agent(
+                    &mut connection,
+                    Request::GetEntry {
+                        entry: name.clone(),
+                    },
+                )
+                .await
+                {
+                    Response::PasswordEntry { entry } => entry,
+                    Response::Error(e) => return eprintln!("Error: {}", e),
+                    _ => return eprintln!("Unexpected response type."),
+                };
+                prompt_entry_update(&entry)
+            } else {
+                let mut update = EntryUpdate::default();
+                update.username = username;
+                // an empty list means the option wasn't given, unless clearing was asked for
+                update.urls = (clear_urls || !details.urls.is_empty()).then_some(details.urls);
+                update.notes = details.notes;
+                update.tags = (clear_tags || !details.tags.is_empty()).then_some(details.tags);
+                if details.otp {
+                    update.otp = Some(prompt_otp_uri());
+                }
+                update.removed_fields = removed_fields;
+                if password {
+                    update.password = Some(rpassword::prompt_password("New password: ").unwrap());
+                }
+                for (name, value) in details.fields {
+                    update
+                        .custom_fields
+                        .push(CustomField::new(&name, &value, false));
+                }
+                for name in details.secret_fields {
+                    let value = rpassword::prompt_password(format!("Value for {name}: ")).unwrap();
+                    update
+                        .custom_fields
+                        .push(CustomField::new(&name, &value, true));
+                }
+                update
+            };
+            if update.is_empty() {
+                println!("Nothing to change.");
+                return;
+            }
+            match send_request_to_agent(
+                &mut connection,
+                Request::UpdateEntry {
+                    entry: name,
+                    update,
+                },
+            )
+            .await
+            {
+                Response::Ok => println!("Entry updated!"),
+                Response::Error(e) => eprintln!("Error trying to update entry: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::History {
+            name,
+            restore: Some(number),
+        } => {
+            let Some(index) = number.checked_sub(1) else {
+                return eprintln!("History positions start at 1.");
+            };
+            let request = Request::RestorePassword { entry: name, index };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::Ok => println!("Password {number} restored!"),
+                Response::Error(e) => eprintln!("Error trying to restore password: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::History {
+            name,
+            restore: None,
+        } => {
+            match send_request_to_agent(&mut connection, Request::GetEntry { entry: name }).await {
+                Response::PasswordEntry { entry } => {
+                    if entry.history.is_empty() {
+                        println!("No previous passwords for {}.", entry.name);
+                    }
+                    for (number, item) in entry.history.iter().enumerate() {
+                        println!(
+                            "{}. {} (replaced {})",
+                            number + 1,
+                            item.password,
+                            format_timestamp(item.changed_at)
+                        );
+                    }
+                }
+                Response::Error(e) => eprintln!("Error: {}", e),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
         Commands::Show { name } => {
-            match send_request_to_agent(stream, Request::GetEntry { name }).await {
+            match send_request_to_agent(&mut connection, Request::GetEntry { entry: name }).await {
                 Response::PasswordEntry { entry } => {
-                    println!(
-                        "Entry found:\nName: {}\nUsername: {}\nPassword: {}",
-                        entry.name, entry.username, entry.password
-                    );
+                    println!("Entry found:");
+                    print_entry(&entry);
                 }
                 Response::Error(e) => eprintln!("Error: {}", e),
                 _ => eprintln!("Unexpected response type."),
             }
         }
-        Commands::List => match send_request_to_agent(stream, Request::ListEntries).await {
-            Response::PasswordList { list } => {
-                for entry in list.passwords {
-                    println!(
-                        "Name: {}\nUsername: {}\nPassword: {}",
-                        entry.name, entry.username, entry.password
-                    );
+        Commands::List => {
+            match send_request_to_agent(&mut connection, Request::ListEntries).await {
+                Response::EntryList { entries, folders } => print_tree(&entries, &folders, "", 0),
+                Response::Error(e) => eprintln!("Error: {}", e),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+
+        Commands::Otp { name } => {
+            match send_request_to_agent(&mut connection, Request::GetOtp { entry: name }).await {
+                Response::OtpCode { otp } => match otp.valid_for {
+                    Some(seconds) => println!("{} (valid for {seconds}s)", otp.code),
+                    None => println!("{}", otp.code),
+                },
+                Response::Error(e) => eprintln!("Error: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Attach {
+            name,
+            file,
+            attachment_name,
+        } => {
+            let data = std::fs::read(&file).expect("Error reading file");
+            let attachment_name = attachment_name.unwrap_or_else(|| {
+                file.file_name()
+                    .expect("Not a file")
+                    .to_string_lossy()
+                    .to_string()
+            });
+            let request = Request::StartAttachmentUpload {
+                entry: name,
+                name: attachment_name,
+                size: data.len() as u64,
+            };
+            let upload = match send_request_to_agent(&mut connection, request).await {
+                Response::UploadStarted { upload } => upload,
+                Response::Error(e) => return eprintln!("Error trying to attach file: {e}"),
+                _ => return eprintln!("Unexpected response type."),
+            };
+            // send every chunk before waiting for the first answer
+            let mut chunks = Vec::new();
+            for chunk in data.chunks(ATTACHMENT_TRANSFER_CHUNK) {
+                let request = Request::UploadAttachmentChunk {
+                    upload,
+                    data: chunk.to_vec(),
+                };
+                let id = connection
+                    .send(request)
+                    .await
+                    .expect("Failed to write to socket");
+                chunks.push(id);
+            }
+            for (index, id) in chunks.iter().enumerate() {
+                let error = match connection.receive(*id).await {
+                    Ok(Response::Ok) => continue,
+                    Ok(Response::Error(e)) => e.to_string(),
+                    Ok(_) => "Unexpected response type.".to_string(),
+                    Err(e) => panic!("Failed to read response: {e}"),
+                };
+                for id in &chunks[index + 1..] {
+                    let _ = connection.cancel(*id).await;
                 }
+                return eprintln!("Error trying to attach file: {error}");
             }
-            Response::Error(e) => eprintln!("Error: {}", e),
-            _ => eprintln!("Unexpected response type."),
-        },
+            let request = Request::FinishAttachmentUpload { upload };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::Attachment { attachment } => {
+                    println!("Attached {} ({} bytes)!", attachment.name, attachment.size)
+                }
+                Response::Error(e) => eprintln!("Error trying to attach file: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Extract {
+            name,
+            attachment,
+            output,
+        } => {
+            let output = output.unwrap_or_else(|| attachment.clone().into());
+            let mut file = match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&output)
+            {
+                Ok(file) => file,
+                Err(e) => return eprintln!("Error creating {}: {e}", output.display()),
+            };
 
+            let mut offset = 0;
+            let result = loop {
+                let request = Request::GetAttachmentChunk {
+                    entry: name.clone(),
+                    attachment: attachment.clone(),
+                    offset,
+                };
+                match send_request_to_agent(&mut connection, request).await {
+                    Response::AttachmentChunk { data, size } => {
+                        file.write_all(&data).expect("Error writing file");
+                        offset += data.len() as u64;
+                        if offset >= size || data.is_empty() {
+                            break Ok(());
+                        }
+                    }
+                    Response::Error(e) => break Err(e.to_string()),
+                    _ => break Err("Unexpected response type.".to_string()),
+                }
+            };
+            match result {
+                Ok(()) => println!("Extracted {attachment} to {}", output.display()),
+                Err(e) => {
+                    drop(file);
+                    let _ = std::fs::remove_file(&output);
+                    eprintln!("Error trying to extract attachment: {e}");
+                }
+            }
+        }
+        Commands::Detach { name, attachment } => {
+            let request = Request::RemoveAttachment {
+                entry: name,
+                attachment,
+            };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::Ok => println!("Attachment removed!"),
+                Response::Error(e) => eprintln!("Error trying to remove attachment: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Search {
+            query,
+            tags,
+            url,
+            username,
+            folder,
+            fuzzy,
+        } => {
+            let filters = SearchFilters {
+                tags,
+                url,
+                username,
+                folder,
+                fuzzy,
+            };
+            match send_request_to_agent(&mut connection, Request::SearchEntries { query, filters })
+                .await
+            {
+                Response::EntrySummaries { entries } if entries.is_empty() => {
+                    println!("No matching entries.")
+                }
+                Response::EntrySummaries { entries } => {
+                    for entry in entries {
+                        print_summary(&entry);
+                    }
+                }
+                Response::Error(e) => eprintln!("Error: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Rename { name, new_name } => {
+            let request = Request::RenameEntry {
+                entry: name,
+                new_name: new_name.clone(),
+            };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::Ok => println!("Entry renamed to {new_name}!"),
+                Response::Error(e) => eprintln!("Error trying to rename entry: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Move { name, folder } => {
+            let request = Request::MoveEntry {
+                entry: name,
+                folder: folder.clone(),
+            };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::Ok => println!("Entry moved to /{}", normalize_folder(&folder)),
+                Response::Error(e) => eprintln!("Error trying to move entry: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Folder { action } => {
+            let request = match action {
+                FolderAction::Create { path } => Request::CreateFolder { path },
+                FolderAction::Move { from, to } => Request::MoveFolder { from, to },
+                FolderAction::Delete { path } => Request::DeleteFolder { path },
+            };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::Ok => println!("Folders updated!"),
+                Response::Error(e) => eprintln!("Error: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Trash { action } => {
+            let request = match action {
+                TrashAction::List => Request::ListTrash,
+                TrashAction::Restore { name } => Request::RestoreEntry { entry: name },
+                TrashAction::Purge { name } => Request::PurgeTrash { entry: name },
+                TrashAction::Retention { days } => Request::SetTrashRetention { days },
+            };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::TrashList { entries } if entries.is_empty() => {
+                    println!("The trash is empty.")
+                }
+                Response::TrashList { entries } => {
+                    for trashed in entries {
+                        print!("Deleted {}: ", format_timestamp(trashed.deleted_at));
+                        print_summary(&trashed.entry);
+                    }
+                }
+                Response::Ok => println!("Trash updated!"),
+                Response::Error(e) => eprintln!("Error: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
+        Commands::Backup { action } => {
+            let request = match action {
+                BackupAction::List => Request::ListBackups,
+                BackupAction::Restore { name } => Request::RestoreBackup { backup: name },
+                BackupAction::Config { keep, dir } => Request::SetBackupPolicy {
+                    policy: BackupPolicy { keep, dir },
+                },
+            };
+            match send_request_to_agent(&mut connection, request).await {
+                Response::BackupList { backups } if backups.is_empty() => {
+                    println!("No backups yet.")
+                }
+                Response::BackupList { backups } => {
+                    for backup in backups {
+                        println!(
+                            "{}  {}  {} bytes",
+                            backup.name,
+                            format_timestamp(backup.created_at / 1000),
+                            backup.size
+                        );
+                    }
+                }
+                Response::Ok => println!("Backups updated!"),
+                Response::Error(e) => eprintln!("Error: {e}"),
+                _ => eprintln!("Unexpected response type."),
+            }
+        }
         Commands::Remove { name } => {
-            match send_request_to_agent(stream, Request::RemoveEntry { name }).await {
-                Response::Ok => println!("Sucessfully Removed Entry."),
+            match send_request_to_agent(&mut connection, Request::RemoveEntry { entry: name }).await
+            {
+                Response::Ok => println!("Moved entry to the trash."),
                 Response::Error(e) => eprintln!("Error: {}", e),
                 _ => eprintln!("Unexpected response type."),
             }
         }
 
-        Commands::Add { name, username } => {
+        Commands::Add {
+            name,
+            username,
+            details,
+        } => {
             let user_password = rpassword::prompt_password("Your password: ").unwrap();
-            let password_entry = PasswordEntry::new(&name, &username, &user_password);
+            let (folder, entry_name) = split_path(&name);
+            let mut password_entry = PasswordEntry::new(&entry_name, &username, &user_password);
+            password_entry.folder = folder;
+            apply_entry_details(&mut password_entry, details);
             let response = send_request_to_agent(
-                stream,
+                &mut connection,
                 Request::AddEntry {
                     entry: password_entry,
                 },
@@ -182,22 +877,193 @@ pub async fn handle_agent_command(command: Commands, stream: UnixStream) {
     }
 }
 
-async fn send_request_to_agent(mut stream: UnixStream, request: Request) -> Response {
-    let json_bytes = serde_json::to_vec(&request).expect("Serialization failed");
-    stream
-        .write_all(&json_bytes)
-        .await
-        .expect("Failed to write to socket");
+fn apply_entry_details(entry: &mut PasswordEntry, details: EntryDetails) {
+    entry.urls = details.urls;
+    entry.notes = details.notes.unwrap_or_default();
+    entry.tags = details.tags;
+    if details.otp {
+        entry.otp = Some(prompt_otp_uri()).filter(|uri| !uri.is_empty());
+    }
+    for (name, value) in details.fields {
+        entry
+            .custom_fields
+            .push(CustomField::new(&name, &value, false));
+    }
+    for name in details.secret_fields {
+        let value = rpassword::prompt_password(format!("Value for {name}: ")).unwrap();
+        entry
+            .custom_fields
+            .push(CustomField::new(&name, &value, true));
+    }
+}
 
-    let mut buf = vec![0u8; 4096]; // A reasonable buffer size
-    let n = stream
-        .read(&mut buf)
-        .await
-        .expect("Failed to read from socket");
+/// Read hidden, since the URI contains the seed. An empty answer removes the one-time password.
+fn prompt_otp_uri() -> String {
+    rpassword::prompt_password("otpauth:// URI: ")
+        .unwrap()
+        .trim()
+        .to_string()
+}
 
-    if n == 0 {
-        panic!("Agent closed connection unexpectedly");
+/// Walks through every field of `entry`. Enter keeps the current value and `-` clears it.
+fn prompt_entry_update(entry: &PasswordEntry) -> EntryUpdate {
+    let split = |value: String| -> Vec<String> {
+        value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    };
+
+    println!(
+        "Editing {} (enter keeps the current value, - clears it)",
+        entry.name
+    );
+    let mut update = EntryUpdate::default();
+    update.username = prompt_line("Username", &entry.username);
+    update.urls = prompt_line("URLs (comma separated)", &entry.urls.join(", ")).map(split);
+    update.tags = prompt_line("Tags (comma separated)", &entry.tags.join(", ")).map(split);
+    update.notes = prompt_line("Notes", &entry.notes);
+    let password = rpassword::prompt_password("Password (enter keeps the current one): ").unwrap();
+    if !password.is_empty() {
+        update.password = Some(password);
     }
+    for field in &entry.custom_fields {
+        let value = if field.sensitive {
+            Some(rpassword::prompt_password(format!("{} (hidden): ", field.name)).unwrap())
+                .filter(|value| !value.is_empty())
+        } else {
+            prompt_line(&field.name, &field.value)
+        };
+        match value.as_deref() {
+            None => {}
+            Some("-") | Some("") => update.removed_fields.push(field.name.clone()),
+            Some(value) => {
+                update
+                    .custom_fields
+                    .push(CustomField::new(&field.name, value, field.sensitive))
+            }
+        }
+    }
+    update
+}
 
-    serde_json::from_slice(&buf[..n]).expect("Failed to parse response")
+fn prompt_line(label: &str, current: &str) -> Option<String> {
+    print!("{label} [{current}]: ");
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    match line.trim() {
+        "" => None,
+        "-" => Some(String::new()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Prints the entries and subfolders of `folder`, indented by `depth`.
+fn print_tree(entries: &[EntrySummary], folders: &[String], folder: &str, depth: usize) {
+    let indent = "  ".repeat(depth);
+    for subfolder in folders {
+        let (parent, name) = split_path(subfolder);
+        if parent == folder {
+            println!("{indent}{name}/");
+            print_tree(entries, folders, subfolder, depth + 1);
+        }
+    }
+
+    let mut entries: Vec<_> = entries
+        .iter()
+        .filter(|entry| entry.folder == folder)
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        println!("{indent}{} ({})", entry.name, entry.username);
+    }
+}
+
+fn print_summary(entry: &EntrySummary) {
+    let path = if entry.folder.is_empty() {
+        entry.name.clone()
+    } else {
+        format!("{}/{}", entry.folder, entry.name)
+    };
+    if entry.tags.is_empty() {
+        println!("{path} ({})", entry.username);
+    } else {
+        println!("{path} ({}) [{}]", entry.username, entry.tags.join(", "));
+    }
+}
+
+fn print_entry(entry: &PasswordEntry) {
+    println!(
+        "ID: {}\nName: {}\nUsername: {}\nPassword: {}",
+        entry.id, entry.name, entry.username, entry.password
+    );
+    if !entry.folder.is_empty() {
+        println!("Folder: {}", entry.folder);
+    }
+    for url in &entry.urls {
+        println!("URL: {url}");
+    }
+    if !entry.tags.is_empty() {
+        println!("Tags: {}", entry.tags.join(", "));
+    }
+    for field in &entry.custom_fields {
+        println!("{}: {}", field.name, field.value);
+    }
+    for attachment in &entry.attachments {
+        println!(
+            "Attachment: {} ({} bytes)",
+            attachment.name, attachment.size
+        );
+    }
+    if entry.otp.is_some() {
+        println!("One-time password: configured (see `vpassword otp`)");
+    }
+    if !entry.notes.is_empty() {
+        println!("Notes:\n{}", entry.notes);
+    }
+    println!(
+        "Created: {}\nModified: {}",
+        format_timestamp(entry.created_at),
+        format_timestamp(entry.modified_at)
+    );
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    // days since the epoch to a proleptic Gregorian date, see Howard Hinnant's `civil_from_days`
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let seconds = timestamp % 86_400;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60
+    )
+}
+
+async fn send_request_to_agent(connection: &mut AgentConnection, request: Request) -> Response {
+    let id = connection
+        .send(request)
+        .await
+        .expect("Failed to write to socket");
+
+    match connection.receive(id).await {
+        Ok(response) => response,
+        Err(e) => panic!("Failed to read response: {e}"),
+    }
 }
@@ -0,0 +1,272 @@
+use std::{
+    fs::{self, DirBuilder},
+    io::Write,
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    encryption::{open, seal},
+    errors::VaultError,
+    folder::validate_name,
+    models::{Attachment, EncryptionData, PasswordEntry, Vault},
+    vault::{unix_timestamp, write_atomic},
+};
+
+/// How much attachment data travels in one request or response. Reads never
+/// cross a storage chunk, so there is no point in asking for more than one.
+pub const ATTACHMENT_TRANSFER_CHUNK: usize = STORAGE_CHUNK_SIZE;
+pub const MAX_ATTACHMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// Attachments are sealed in chunks of this size, so reading a part of one
+/// only decrypts the chunk it falls into.
+const STORAGE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The sidecar file holding an attachment's encrypted data.
+#[derive(Serialize, Deserialize)]
+struct AttachmentBlob {
+    chunks: Vec<EncryptionData>,
+}
+
+/// Ties a chunk to its attachment and position, so chunks can't be swapped,
+/// reordered or dropped without decryption failing.
+fn chunk_aad(id: Uuid, index: usize, count: usize) -> Vec<u8> {
+    format!("{id}:{index}:{count}").into_bytes()
+}
+
+impl PasswordEntry {
+    /// Finds an attachment by its ID, falling back to its name.
+    pub fn attachment(&self, id_or_name: &str) -> Option<&Attachment> {
+        self.attachments
+            .iter()
+            .find(|attachment| attachment.id.to_string() == id_or_name)
+            .or_else(|| {
+                self.attachments
+                    .iter()
+                    .find(|attachment| attachment.name == id_or_name)
+            })
+    }
+}
+
+impl Vault {
+    /// Directory next to the vault file holding one encrypted file per attachment.
+    pub fn attachment_dir(&self) -> PathBuf {
+        let mut dir_name = self.path.file_name().unwrap_or_default().to_owned();
+        dir_name.push(".attachments");
+        self.path.with_file_name(dir_name)
+    }
+
+    fn attachment_path(&self, id: Uuid) -> PathBuf {
+        self.attachment_dir().join(id.to_string())
+    }
+
+    /// Encrypts `data` into its own file and records it on the entry.
+    pub fn add_attachment(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: &str,
+        name: &str,
+        data: &[u8],
+    ) -> Result<Attachment, VaultError> {
+        validate_name(name)?;
+        if data.len() as u64 > MAX_ATTACHMENT_SIZE {
+            return Err(VaultError::AttachmentTooLarge(MAX_ATTACHMENT_SIZE));
+        }
+        let mut password_list = self.list(vault_key)?;
+        let entry = password_list.find_mut(id_or_name)?;
+        if entry.attachment(name).is_some() {
+            return Err(VaultError::DuplicateAttachment(name.to_string()));
+        }
+
+        let attachment = Attachment {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            size: data.len() as u64,
+            created_at: unix_timestamp(),
+        };
+        let count = data.len().div_ceil(STORAGE_CHUNK_SIZE).max(1);
+        let chunks = (0..count)
+            .map(|index| {
+                let start = index * STORAGE_CHUNK_SIZE;
+                let end = data.len().min(start + STORAGE_CHUNK_SIZE);
+                seal(
+                    self.encryption.cipher,
+                    vault_key,
+                    &data[start..end],
+                    &chunk_aad(attachment.id, index, count),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(self.attachment_dir())?;
+        let blob = serde_json::to_vec(&AttachmentBlob { chunks })?;
+        let path = self.attachment_path(attachment.id);
+        write_atomic(&path, |file| file.write_all(&blob))?;
+
+        entry.attachments.push(attachment.clone());
+        entry.modified_at = unix_timestamp();
+        let saved = self
+            .encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())
+            .and_then(|_| self.save_to_file());
+        if let Err(e) = saved {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+
+        Ok(attachment)
+    }
+
+    /// Decrypts up to `len` bytes of an attachment starting at `offset`,
+    /// returning them together with the attachment's total size.
+    pub fn read_attachment(
+        &self,
+        vault_key: &[u8],
+        id_or_name: &str,
+        attachment: &str,
+        offset: u64,
+        len: usize,
+    ) -> Result<(Zeroizing<Vec<u8>>, u64), VaultError> {
+        let password_list = self.list(vault_key)?;
+        let entry = password_list
+            .position(id_or_name)
+            .map(|index| &password_list.passwords[index])
+            .ok_or_else(|| VaultError::NoSuchEntry(id_or_name.to_string()))?;
+        let attachment = entry
+            .attachment(attachment)
+            .ok_or_else(|| VaultError::NoSuchAttachment(attachment.to_string()))?;
+        if offset >= attachment.size {
+            return Ok((Zeroizing::new(Vec::new()), attachment.size));
+        }
+
+        let blob: AttachmentBlob =
+            serde_json::from_slice(&fs::read(self.attachment_path(attachment.id))?)?;
+        let index = (offset / STORAGE_CHUNK_SIZE as u64) as usize;
+        let chunk = blob
+            .chunks
+            .get(index)
+            .ok_or_else(|| VaultError::NoSuchAttachment(attachment.name.clone()))?;
+        let plaintext = Zeroizing::new(open(
+            vault_key,
+            chunk,
+            &chunk_aad(attachment.id, index, blob.chunks.len()),
+        )?);
+
+        let start = (offset % STORAGE_CHUNK_SIZE as u64) as usize;
+        let end = plaintext.len().min(start + len);
+        Ok((
+            Zeroizing::new(plaintext[start.min(end)..end].to_vec()),
+            attachment.size,
+        ))
+    }
+
+    pub fn remove_attachment(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: &str,
+        attachment: &str,
+    ) -> Result<(), VaultError> {
+        let mut password_list = self.list(vault_key)?;
+        let entry = password_list.find_mut(id_or_name)?;
+        let id = entry
+            .attachment(attachment)
+            .ok_or_else(|| VaultError::NoSuchAttachment(attachment.to_string()))?
+            .id;
+        entry.attachments.retain(|attachment| attachment.id != id);
+        entry.modified_at = unix_timestamp();
+
+        self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
+        self.save_to_file()?;
+        self.remove_attachment_files(&[id]);
+        Ok(())
+    }
+
+    /// Deletes the sidecar files of attachments that are no longer referenced.
+    /// Failures are ignored, a leftover file can't be decrypted into anything useful
+    /// without the vault key and is never read again.
+    pub(crate) fn remove_attachment_files(&self, ids: &[Uuid]) {
+        for id in ids {
+            let _ = fs::remove_file(self.attachment_path(*id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::test_vault;
+
+    fn read_all(vault: &Vault, key: &[u8], attachment: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        loop {
+            let (chunk, size) = vault
+                .read_attachment(key, "server", attachment, data.len() as u64, 40_000)
+                .unwrap();
+            data.extend_from_slice(&chunk);
+            if data.len() as u64 >= size {
+                return data;
+            }
+        }
+    }
+
+    #[test]
+    fn attachments_round_trip_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("server", "root", "hunter2"))
+            .unwrap();
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+
+        let attachment = vault
+            .add_attachment(&key, "server", "id_ed25519", &data)
+            .unwrap();
+        assert_eq!(attachment.size, data.len() as u64);
+        assert_eq!(read_all(&vault, &key, "id_ed25519"), data);
+        assert!(matches!(
+            vault.add_attachment(&key, "server", "id_ed25519", b"again"),
+            Err(VaultError::DuplicateAttachment(_))
+        ));
+
+        let blob = fs::read(vault.attachment_path(attachment.id)).unwrap();
+        assert!(!blob.windows(4).any(|window| window == &data[1000..1004]));
+
+        vault
+            .remove_attachment(&key, "server", &attachment.id.to_string())
+            .unwrap();
+        assert!(!vault.attachment_path(attachment.id).exists());
+        assert!(matches!(
+            vault.read_attachment(&key, "server", "id_ed25519", 0, 10),
+            Err(VaultError::NoSuchAttachment(_))
+        ));
+    }
+
+    #[test]
+    fn swapped_chunks_fail_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("server", "root", "hunter2"))
+            .unwrap();
+        let data = vec![7u8; STORAGE_CHUNK_SIZE * 2];
+        let attachment = vault
+            .add_attachment(&key, "server", "disk.img", &data)
+            .unwrap();
+
+        let path = vault.attachment_path(attachment.id);
+        let mut blob: AttachmentBlob = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        blob.chunks.swap(0, 1);
+        fs::write(&path, serde_json::to_vec(&blob).unwrap()).unwrap();
+
+        assert!(
+            vault
+                .read_attachment(&key, "server", "disk.img", 0, 10)
+                .is_err()
+        );
+    }
+}
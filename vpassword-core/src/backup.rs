@@ -0,0 +1,278 @@
+use std::{
+    cmp::Reverse,
+    fs::{self, DirBuilder},
+    io::Write,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{errors::VaultError, models::Vault, vault::write_atomic};
+
+/// How many copies of the vault to keep and where. Backups are plain copies
+/// of the vault file, so they are exactly as encrypted as the vault itself.
+/// Attachment files are not copied: a backup taken before an attachment was
+/// removed still lists it, but its contents are gone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupPolicy {
+    /// Zero turns backups off.
+    pub keep: usize,
+    /// Defaults to a `<vault>.backups` directory next to the vault.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self {
+            keep: 10,
+            dir: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupInfo {
+    /// File name inside the backup directory, also what identifies it for a restore.
+    pub name: String,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub size: u64,
+}
+
+impl Vault {
+    pub fn backup_policy(&self) -> BackupPolicy {
+        self.backup_policy.clone().unwrap_or_default()
+    }
+
+    /// The policy is part of the header, so changing it re-encrypts the data.
+    pub fn set_backup_policy(
+        &mut self,
+        data_key: &[u8],
+        policy: BackupPolicy,
+    ) -> Result<(), VaultError> {
+        let plaintext = Zeroizing::new(self.decrypt_data(data_key)?);
+        self.backup_policy = Some(policy);
+        self.encrypt_data(data_key, &plaintext)?;
+        self.save_to_file()
+    }
+
+    pub fn backup_dir(&self) -> PathBuf {
+        self.backup_policy()
+            .dir
+            .unwrap_or_else(|| default_backup_dir(&self.path))
+    }
+
+    /// Copies the vault file as it is on disk into the backup directory and
+    /// drops the oldest copies beyond the policy's count. Does nothing before
+    /// the vault was first written.
+    pub(crate) fn back_up(&self) -> Result<(), VaultError> {
+        let policy = self.backup_policy();
+        if policy.keep == 0 || !self.path.exists() {
+            return Ok(());
+        }
+
+        let dir = self.backup_dir();
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        // like `unix_timestamp`, a clock before the epoch must not stop the save
+        let mut created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        let backup_path = loop {
+            let path = dir.join(format!("{}{created_at}.bak", backup_prefix(&self.path)));
+            if !path.exists() {
+                break path;
+            }
+            created_at += 1;
+        };
+        let data = fs::read(&self.path)?;
+        write_atomic(&backup_path, |file| file.write_all(&data))?;
+
+        for old in self.backups()?.iter().skip(policy.keep) {
+            fs::remove_file(dir.join(&old.name))?;
+        }
+        Ok(())
+    }
+
+    /// Lists the backups of this vault, newest first.
+    pub fn backups(&self) -> Result<Vec<BackupInfo>, VaultError> {
+        backups_in(&self.backup_dir(), &self.path)
+    }
+
+    /// Replaces the vault with the named backup, after backing up the current
+    /// state so the restore itself can be undone. Attachments removed since the
+    /// backup stay listed without their files, which `check_vault` warns about.
+    pub fn restore_backup(&mut self, name: &str) -> Result<(), VaultError> {
+        // only names from the listing, so `name` can't point outside the backup directory
+        if !self.backups()?.iter().any(|backup| backup.name == name) {
+            return Err(VaultError::NoSuchBackup(name.to_string()));
+        }
+        let backup_path = self.backup_dir().join(name);
+        Vault::new_from_file(&backup_path)?;
+
+        self.back_up()?;
+        let data = fs::read(&backup_path)?;
+        write_atomic(&self.path, |file| file.write_all(&data))?;
+        *self = Vault::new_from_file(&self.path)?;
+        Ok(())
+    }
+}
+
+pub fn default_backup_dir(vault_path: &Path) -> PathBuf {
+    let mut dir_name = vault_path.file_name().unwrap_or_default().to_owned();
+    dir_name.push(".backups");
+    vault_path.with_file_name(dir_name)
+}
+
+fn backup_prefix(vault_path: &Path) -> String {
+    format!(
+        "{}.",
+        vault_path.file_name().unwrap_or_default().to_string_lossy()
+    )
+}
+
+/// Lists the backups of the vault at `vault_path` found in `dir`, newest
+/// first. Works without parsing the vault, which may be the broken part.
+pub fn backups_in(dir: &Path, vault_path: &Path) -> Result<Vec<BackupInfo>, VaultError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let prefix = backup_prefix(vault_path);
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let created_at = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".bak"))
+            .and_then(|timestamp| timestamp.parse().ok());
+        if let Some(created_at) = created_at {
+            backups.push(BackupInfo {
+                name,
+                created_at,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    backups.sort_by_key(|backup| Reverse(backup.created_at));
+    Ok(backups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        check::{Outcome, check_vault},
+        models::PasswordEntry,
+        vault::test_vault,
+    };
+
+    #[test]
+    fn every_write_is_backed_up_and_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .set_backup_policy(
+                &key,
+                BackupPolicy {
+                    keep: 3,
+                    dir: Some(dir.path().join("backups")),
+                },
+            )
+            .unwrap();
+
+        for name in ["a", "b", "c", "d", "e"] {
+            vault
+                .add_entry(&key, PasswordEntry::new(name, "john", "hunter2"))
+                .unwrap();
+        }
+        let backups = vault.backups().unwrap();
+        assert_eq!(backups.len(), 3);
+        assert!(backups[0].created_at > backups[2].created_at);
+
+        // the newest backup is the vault before "e" was added
+        let newest = Vault::new_from_file(&vault.backup_dir().join(&backups[0].name)).unwrap();
+        assert_eq!(newest.list(&key).unwrap().passwords.len(), 4);
+    }
+
+    #[test]
+    fn backup_policy_is_bound_to_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .set_backup_policy(&key, BackupPolicy::default())
+            .unwrap();
+
+        // turning backups off behind the user's back must not go unnoticed
+        let mut disabled = Vault::new_from_file(&vault.path).unwrap();
+        disabled.backup_policy = Some(BackupPolicy { keep: 0, dir: None });
+        assert!(matches!(
+            disabled.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+        disabled.backup_policy = None;
+        assert!(matches!(
+            disabled.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+    }
+
+    #[test]
+    fn restoring_a_backup_can_be_undone() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+        let before_add = vault.backups().unwrap()[0].name.clone();
+
+        vault.restore_backup(&before_add).unwrap();
+        assert!(vault.list(&key).unwrap().passwords.is_empty());
+
+        let before_restore = vault.backups().unwrap()[0].name.clone();
+        vault.restore_backup(&before_restore).unwrap();
+        assert_eq!(vault.list(&key).unwrap().passwords.len(), 1);
+
+        assert!(matches!(
+            vault.restore_backup("../test.vault"),
+            Err(VaultError::NoSuchBackup(_))
+        ));
+    }
+
+    #[test]
+    fn restored_backup_keeps_removed_attachments_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+        vault
+            .add_attachment(&key, "github", "codes.txt", b"123456")
+            .unwrap();
+        vault
+            .remove_attachment(&key, "github", "codes.txt")
+            .unwrap();
+        let with_attachment = vault.backups().unwrap()[0].name.clone();
+
+        // only the vault file comes back, the attachment's file stays deleted
+        vault.restore_backup(&with_attachment).unwrap();
+        let entry = vault.get_entry(&key, "github").unwrap();
+        assert_eq!(entry.attachments.len(), 1);
+        assert!(matches!(
+            vault.read_attachment(&key, "github", "codes.txt", 0, 6),
+            Err(VaultError::Io(_))
+        ));
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert!(report.checks.iter().any(|check| matches!(
+            &check.outcome,
+            Outcome::Warning(missing) if missing.contains("github/codes.txt")
+        )));
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use argon2::Params;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde_json::Value;
+use zeroize::Zeroizing;
+
+use crate::{
+    backup::{BackupInfo, backups_in, default_backup_dir},
+    encryption::open,
+    errors::VaultError,
+    models::{Argon2Params, EncryptionData, KeySlotKind, PasswordList, Vault},
+    vault::{VAULT_VERSION, write_atomic},
+};
+
+/// AEAD tags are 16 bytes for both supported ciphers, nothing shorter can be a ciphertext.
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// Something worth knowing that does not make the vault unusable.
+    Warning(String),
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnosis {
+    Healthy,
+    /// The file is intact, the password just does not open it.
+    WrongPassword,
+    Corrupted,
+    /// The structure is intact, but without a password the contents could not be checked.
+    Unverified,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckReport {
+    pub checks: Vec<Check>,
+    pub diagnosis: Diagnosis,
+}
+
+impl CheckReport {
+    fn record(&mut self, name: &'static str, result: Result<(), String>) -> bool {
+        let passed = result.is_ok();
+        let outcome = match result {
+            Ok(()) => Outcome::Passed,
+            Err(reason) => Outcome::Failed(reason),
+        };
+        self.checks.push(Check { name, outcome });
+        passed
+    }
+
+    fn skip(&mut self, names: &[&'static str], reason: &str) {
+        for name in names {
+            self.checks.push(Check {
+                name,
+                outcome: Outcome::Skipped(reason.to_string()),
+            });
+        }
+    }
+
+    fn finish(mut self, password_checked: bool) -> Self {
+        let failed = self
+            .checks
+            .iter()
+            .any(|check| matches!(check.outcome, Outcome::Failed(_)));
+        self.diagnosis = match self.diagnosis {
+            Diagnosis::WrongPassword => Diagnosis::WrongPassword,
+            _ if failed => Diagnosis::Corrupted,
+            _ if !password_checked => Diagnosis::Unverified,
+            _ => Diagnosis::Healthy,
+        };
+        self
+    }
+}
+
+const SECRET_CHECKS: [&str; 3] = ["unlock", "decryption", "entries"];
+
+/// Runs every check on the vault file at `path` without modifying it. The
+/// contents are only checked when `master_password` is given.
+pub fn check_vault(path: &Path, master_password: Option<&[u8]>) -> CheckReport {
+    let mut report = CheckReport {
+        checks: Vec::new(),
+        diagnosis: Diagnosis::Healthy,
+    };
+
+    let bytes = fs::read(path);
+    if !report.record(
+        "readable",
+        bytes.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    ) {
+        return report.finish(false);
+    }
+    let json = serde_json::from_slice::<Value>(&bytes.unwrap());
+    if !report.record("json", json.as_ref().map(|_| ()).map_err(|e| e.to_string())) {
+        return report.finish(false);
+    }
+    let vault = serde_json::from_value::<Vault>(json.unwrap());
+    if !report.record(
+        "structure",
+        vault.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    ) {
+        return report.finish(false);
+    }
+    let mut vault = vault.unwrap();
+    vault.path = path.to_owned();
+
+    let version_ok = report.record("version", vault.check_version().map_err(|e| e.to_string()));
+    let blobs_ok = report.record("encrypted blobs", check_blobs(&vault));
+    let argon2_ok = report.record("argon2 parameters", check_argon2(&vault));
+    let slots_ok = report.record("key slots", check_key_slots(&vault));
+    if !(version_ok && blobs_ok && argon2_ok && slots_ok) {
+        report.skip(&SECRET_CHECKS, "the vault is damaged");
+        return report.finish(false);
+    }
+
+    let Some(master_password) = master_password else {
+        report.skip(&SECRET_CHECKS, "no password given");
+        return report.finish(false);
+    };
+    let vault_key = match unlock(&vault, master_password) {
+        Ok(key) => {
+            report.record("unlock", Ok(()));
+            key
+        }
+        Err(VaultError::WrongPassword) => {
+            report.record(
+                "unlock",
+                Err("the password does not open the vault".to_string()),
+            );
+            report.skip(&SECRET_CHECKS[1..], "the vault could not be unlocked");
+            report.diagnosis = Diagnosis::WrongPassword;
+            return report.finish(true);
+        }
+        Err(e) => {
+            report.record("unlock", Err(e.to_string()));
+            report.skip(&SECRET_CHECKS[1..], "the vault could not be unlocked");
+            return report.finish(true);
+        }
+    };
+
+    let plaintext = vault.decrypt_data(vault_key.as_ref()).map(Zeroizing::new);
+    let decryption = match &plaintext {
+        Ok(_) => Ok(()),
+        // the key is known to be right at this point, so only the vault's own
+        // key check can have failed to open
+        Err(VaultError::Aead) => Err("the vault's key check does not decrypt".to_string()),
+        Err(VaultError::Corrupted(what)) => Err(format!("{what} does not decrypt")),
+        Err(e) => Err(e.to_string()),
+    };
+    if !report.record("decryption", decryption) {
+        report.skip(&SECRET_CHECKS[2..], "the data could not be decrypted");
+        return report.finish(true);
+    }
+
+    let entries = check_entries(&vault, &plaintext.unwrap());
+    if report.record(
+        "entries",
+        entries.as_ref().map(|_| ()).map_err(Clone::clone),
+    ) {
+        let missing = entries.unwrap();
+        report.checks.push(Check {
+            name: "attachments",
+            outcome: match missing.is_empty() {
+                true => Outcome::Passed,
+                false => Outcome::Warning(format!("missing files: {}", missing.join(", "))),
+            },
+        });
+    }
+    report.finish(true)
+}
+
+fn check_blobs(vault: &Vault) -> Result<(), String> {
+    let mut blobs: Vec<(String, &EncryptionData)> = vec![("data".to_string(), &vault.encryption)];
+    if let Some(key_check) = &vault.key_check {
+        blobs.push(("key check".to_string(), key_check));
+    }
+    if let Some(header_check) = &vault.header_check {
+        blobs.push(("header check".to_string(), header_check));
+    }
+    // only the master password slot can be opened during the check, the
+    // others are at least checked to be well formed
+    for slot in &vault.key_slots {
+        blobs.push((format!("{:?} key slot", slot.kind), &slot.wrapped_key));
+        if let Some(key_check) = &slot.key_check {
+            blobs.push((format!("{:?} key slot's key check", slot.kind), key_check));
+        }
+    }
+
+    let mut problems = Vec::new();
+    for (label, blob) in blobs {
+        match STANDARD.decode(&blob.nonce) {
+            Err(e) => problems.push(format!("{label}: nonce is not base64 ({e})")),
+            Ok(nonce) if nonce.len() != blob.cipher.cipher().nonce_len() => problems.push(format!(
+                "{label}: nonce is {} bytes, {:?} needs {}",
+                nonce.len(),
+                blob.cipher,
+                blob.cipher.cipher().nonce_len()
+            )),
+            Ok(_) => {}
+        }
+        match STANDARD.decode(&blob.ciphertext) {
+            Err(e) => problems.push(format!("{label}: ciphertext is not base64 ({e})")),
+            Ok(ciphertext) if ciphertext.len() < TAG_LEN => {
+                problems.push(format!("{label}: ciphertext is truncated"))
+            }
+            Ok(_) => {}
+        }
+    }
+    join_problems(problems)
+}
+
+fn check_argon2(vault: &Vault) -> Result<(), String> {
+    let mut params: Vec<(String, &Argon2Params)> = Vec::new();
+    if let Some(argon2) = &vault.argon2 {
+        params.push(("vault".to_string(), argon2));
+    }
+    for slot in &vault.key_slots {
+        params.push((format!("{:?} key slot", slot.kind), &slot.argon2));
+    }
+
+    let mut problems = Vec::new();
+    for (label, argon2) in params {
+        match STANDARD.decode(&argon2.salt) {
+            Err(e) => problems.push(format!("{label}: salt is not base64 ({e})")),
+            Ok(salt) if salt.len() < argon2::MIN_SALT_LEN => {
+                problems.push(format!("{label}: salt is too short"))
+            }
+            Ok(_) => {}
+        }
+        if let Err(e) = Params::new(
+            argon2.mem_cost,
+            argon2.time_cost,
+            argon2.parallelism,
+            Some(32),
+        ) {
+            problems.push(format!("{label}: {e}"));
+        }
+    }
+    join_problems(problems)
+}
+
+fn check_key_slots(vault: &Vault) -> Result<(), String> {
+    // versions 1 and 2 derive the key from the password directly
+    if vault.version < 3 {
+        return match vault.argon2 {
+            Some(_) => Ok(()),
+            None => Err("no Argon2 parameters to derive the key with".to_string()),
+        };
+    }
+
+    let mut kinds = Vec::new();
+    for slot in &vault.key_slots {
+        if kinds.contains(&slot.kind) {
+            return Err(format!("more than one {:?} key slot", slot.kind));
+        }
+        kinds.push(slot.kind);
+    }
+    if !kinds.contains(&KeySlotKind::MasterPassword) {
+        return Err("there is no master password key slot".to_string());
+    }
+    Ok(())
+}
+
+fn unlock(vault: &Vault, master_password: &[u8]) -> Result<Zeroizing<[u8; 32]>, VaultError> {
+    let key = if vault.version < 3 {
+        let key = Zeroizing::new(vault.derive_vault_key(master_password)?);
+        // version 2 has a key check that tells a wrong password apart from damage,
+        // version 1 only has the data itself
+        let opened = match &vault.key_check {
+            Some(key_check) => open(key.as_ref(), key_check, &[]),
+            None => vault.decrypt_data(key.as_ref()),
+        };
+        match opened {
+            Err(VaultError::Aead) => return Err(VaultError::WrongPassword),
+            opened => opened?,
+        };
+        key
+    } else {
+        unlock_key_slot(vault, master_password)?
+    };
+    Ok(key)
+}
+
+/// Opens the master password slot's key check and wrapped key each on their
+/// own, so whichever of them is damaged gets named.
+fn unlock_key_slot(
+    vault: &Vault,
+    master_password: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, VaultError> {
+    let slot = vault.key_slot(KeySlotKind::MasterPassword)?;
+    let wrapping_key = Zeroizing::new(slot.argon2.derive_key(master_password)?);
+    let key_check = slot
+        .key_check
+        .as_ref()
+        .map(|key_check| open(wrapping_key.as_ref(), key_check, &[]).is_ok());
+    let damaged = |what: &str| VaultError::Corrupted(format!("the MasterPassword key slot{what}"));
+    match (
+        open(wrapping_key.as_ref(), &slot.wrapped_key, &[]),
+        key_check,
+    ) {
+        (Ok(_), Some(false)) => Err(damaged("'s key check")),
+        (Ok(data_key), _) => {
+            let data_key = Zeroizing::new(data_key);
+            Ok(Zeroizing::new(
+                data_key.as_slice().try_into().map_err(|_| damaged(""))?,
+            ))
+        }
+        (Err(VaultError::Aead), Some(true)) => Err(damaged("")),
+        (Err(VaultError::Aead), _) => Err(VaultError::WrongPassword),
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// Checks the decrypted entry list and returns the attachment files it refers to that are missing.
+fn check_entries(vault: &Vault, plaintext: &[u8]) -> Result<Vec<String>, String> {
+    let json: Value = serde_json::from_slice(plaintext).map_err(|e| e.to_string())?;
+    if !json.get("passwords").is_some_and(Value::is_array) {
+        return Err("there is no list of entries".to_string());
+    }
+    // older schemas are checked by the migration when the vault is opened
+    if vault.version < VAULT_VERSION {
+        return Ok(Vec::new());
+    }
+
+    let list: PasswordList = serde_json::from_value(json).map_err(|e| e.to_string())?;
+    let mut ids = HashSet::new();
+    let mut paths = HashSet::new();
+    let mut missing = Vec::new();
+    for entry in &list.passwords {
+        if !ids.insert(entry.id) {
+            return Err(format!("entry ID {} is used twice", entry.id));
+        }
+        if !paths.insert(entry.path()) {
+            return Err(format!("there are two entries at {}", entry.path()));
+        }
+    }
+    let entries = list
+        .passwords
+        .iter()
+        .chain(list.trash.iter().map(|trashed| &trashed.entry));
+    for entry in entries {
+        for attachment in &entry.attachments {
+            if !vault
+                .attachment_dir()
+                .join(attachment.id.to_string())
+                .exists()
+            {
+                missing.push(format!("{}/{}", entry.path(), attachment.name));
+            }
+        }
+    }
+    Ok(missing)
+}
+
+fn join_problems(problems: Vec<String>) -> Result<(), String> {
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems.join("; ")),
+    }
+}
+
+/// Replaces a damaged vault with its newest backup that passes every check
+/// with `master_password`. The damaged file is kept as `<vault>.corrupt`.
+/// `backup_dir` is only needed when the backups are not in the default place
+/// and the vault is too damaged to say where they are.
+pub fn repair_from_backup(
+    path: &Path,
+    backup_dir: Option<&Path>,
+    master_password: &[u8],
+) -> Result<BackupInfo, VaultError> {
+    let backup_dir: PathBuf = match backup_dir {
+        Some(dir) => dir.to_owned(),
+        None => fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Vault>(&bytes).ok())
+            .and_then(|vault| vault.backup_policy.and_then(|policy| policy.dir))
+            .unwrap_or_else(|| default_backup_dir(path)),
+    };
+
+    for backup in backups_in(&backup_dir, path)? {
+        let backup_path = backup_dir.join(&backup.name);
+        if check_vault(&backup_path, Some(master_password)).diagnosis != Diagnosis::Healthy {
+            continue;
+        }
+
+        if path.exists() {
+            let mut corrupt_name = path.file_name().unwrap_or_default().to_owned();
+            corrupt_name.push(".corrupt");
+            fs::copy(path, path.with_file_name(corrupt_name))?;
+        }
+        let data = fs::read(&backup_path)?;
+        write_atomic(path, |file| file.write_all(&data))?;
+        return Ok(backup);
+    }
+    Err(VaultError::NoHealthyBackup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::PasswordEntry, vault::test_vault};
+
+    fn failed(report: &CheckReport) -> Vec<&'static str> {
+        report
+            .checks
+            .iter()
+            .filter(|check| matches!(check.outcome, Outcome::Failed(_)))
+            .map(|check| check.name)
+            .collect()
+    }
+
+    fn has_failure(report: &CheckReport, reason: &str) -> bool {
+        report.checks.iter().any(|check| {
+            matches!(
+                &check.outcome,
+                Outcome::Failed(failure) if failure.contains(reason)
+            )
+        })
+    }
+
+    #[test]
+    fn tells_wrong_password_from_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(report.diagnosis, Diagnosis::Healthy, "{report:?}");
+        assert_eq!(
+            check_vault(&vault.path, None).diagnosis,
+            Diagnosis::Unverified
+        );
+        assert_eq!(
+            check_vault(&vault.path, Some(b"wrong")).diagnosis,
+            Diagnosis::WrongPassword
+        );
+
+        // flip a byte of the ciphertext: the password is still right
+        let mut ciphertext = STANDARD.decode(&vault.encryption.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        vault.encryption.ciphertext = STANDARD.encode(ciphertext);
+        fs::write(&vault.path, serde_json::to_vec(&vault).unwrap()).unwrap();
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(report.diagnosis, Diagnosis::Corrupted);
+        assert_eq!(failed(&report), ["decryption"]);
+        assert!(has_failure(&report, "the vault data does not decrypt"));
+
+        let mut damaged = vault.clone();
+        let key_check = damaged.key_check.as_mut().unwrap();
+        let mut ciphertext = STANDARD.decode(&key_check.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        key_check.ciphertext = STANDARD.encode(ciphertext);
+        fs::write(&vault.path, serde_json::to_vec(&damaged).unwrap()).unwrap();
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(failed(&report), ["decryption"]);
+        assert!(has_failure(&report, "key check does not decrypt"));
+
+        let mut damaged = vault.clone();
+        let wrapped_key = &mut damaged.key_slots[0].wrapped_key;
+        let mut ciphertext = STANDARD.decode(&wrapped_key.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        wrapped_key.ciphertext = STANDARD.encode(ciphertext);
+        fs::write(&vault.path, serde_json::to_vec(&damaged).unwrap()).unwrap();
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(failed(&report), ["unlock"]);
+        assert!(has_failure(&report, "MasterPassword key slot"));
+
+        let mut damaged = vault.clone();
+        let key_check = damaged.key_slots[0].key_check.as_mut().unwrap();
+        let mut ciphertext = STANDARD.decode(&key_check.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        key_check.ciphertext = STANDARD.encode(ciphertext);
+        fs::write(&vault.path, serde_json::to_vec(&damaged).unwrap()).unwrap();
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(failed(&report), ["unlock"]);
+        assert!(has_failure(&report, "MasterPassword key slot's key check"));
+
+        let mut damaged = vault.clone();
+        damaged.key_slots[0].key_check.as_mut().unwrap().ciphertext = "AAAA".to_string();
+        fs::write(&vault.path, serde_json::to_vec(&damaged).unwrap()).unwrap();
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(failed(&report), ["encrypted blobs"]);
+        assert!(has_failure(&report, "MasterPassword key slot's key check"));
+
+        vault.encryption.nonce = "AAAA".to_string();
+        fs::write(&vault.path, serde_json::to_vec(&vault).unwrap()).unwrap();
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(failed(&report), ["encrypted blobs"]);
+
+        fs::write(&vault.path, b"{\"name\": \"test\", \"versi").unwrap();
+        let report = check_vault(&vault.path, Some(b"master"));
+        assert_eq!(report.diagnosis, Diagnosis::Corrupted);
+        assert_eq!(failed(&report), ["json"]);
+    }
+
+    #[test]
+    fn repairs_from_newest_healthy_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+        fs::write(&vault.path, b"garbage").unwrap();
+
+        let backup = repair_from_backup(&vault.path, None, b"master").unwrap();
+        assert!(backup.name.starts_with("test.vault."));
+        assert_eq!(
+            check_vault(&vault.path, Some(b"master")).diagnosis,
+            Diagnosis::Healthy
+        );
+        assert_eq!(
+            fs::read(dir.path().join("test.vault.corrupt")).unwrap(),
+            b"garbage"
+        );
+
+        assert!(matches!(
+            repair_from_backup(&vault.path, None, b"wrong"),
+            Err(VaultError::NoHealthyBackup)
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+use std::marker::PhantomData;
+
+use aes_gcm::{
+    Aes256Gcm,
+    aead::{Aead, AeadCore, KeyInit, Nonce, OsRng, Payload, generic_array::typenum::Unsigned},
+};
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::VaultError;
+
+/// Identifies the AEAD an `EncryptionData` was sealed with. Data written
+/// before this was recorded is AES-256-GCM.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CipherKind {
+    #[default]
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+pub trait VaultCipher {
+    fn nonce_len(&self) -> usize;
+    fn generate_nonce(&self) -> Vec<u8>;
+    fn encrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultError>;
+    fn decrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultError>;
+}
+
+struct AeadCipher<A>(PhantomData<A>);
+
+impl<A: Aead + AeadCore + KeyInit> VaultCipher for AeadCipher<A> {
+    fn nonce_len(&self) -> usize {
+        A::NonceSize::USIZE
+    }
+
+    fn generate_nonce(&self) -> Vec<u8> {
+        A::generate_nonce(&mut OsRng).to_vec()
+    }
+
+    fn encrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultError> {
+        let cipher = A::new_from_slice(key).map_err(|_| VaultError::Aead)?;
+        Ok(cipher.encrypt(
+            nonce_from_slice::<A>(nonce)?,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )?)
+    }
+
+    fn decrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VaultError> {
+        let cipher = A::new_from_slice(key).map_err(|_| VaultError::Aead)?;
+        Ok(cipher.decrypt(
+            nonce_from_slice::<A>(nonce)?,
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )?)
+    }
+}
+
+// `Nonce::from_slice` panics on a length mismatch, and the nonce comes straight from the vault file
+fn nonce_from_slice<A: AeadCore>(nonce: &[u8]) -> Result<&Nonce<A>, VaultError> {
+    if nonce.len() != A::NonceSize::USIZE {
+        return Err(VaultError::Aead);
+    }
+    Ok(Nonce::<A>::from_slice(nonce))
+}
+
+impl CipherKind {
+    pub fn cipher(&self) -> &'static dyn VaultCipher {
+        match self {
+            CipherKind::Aes256Gcm => &AeadCipher::<Aes256Gcm>(PhantomData),
+            CipherKind::XChaCha20Poly1305 => &AeadCipher::<XChaCha20Poly1305>(PhantomData),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encryption::{open, seal},
+        keyslot::generate_data_key,
+        models::{Argon2Params, PasswordEntry, Vault},
+    };
+
+    const CIPHERS: [CipherKind; 2] = [CipherKind::Aes256Gcm, CipherKind::XChaCha20Poly1305];
+
+    #[test]
+    fn round_trips_with_every_cipher() {
+        let key = generate_data_key();
+        for kind in CIPHERS {
+            let sealed = seal(kind, &key, b"secret", b"header").unwrap();
+            assert_eq!(sealed.cipher, kind);
+            assert_eq!(open(&key, &sealed, b"header").unwrap(), b"secret");
+            assert!(open(&key, &sealed, b"other header").is_err());
+            assert!(open(&[0u8; 32], &sealed, b"header").is_err());
+        }
+    }
+
+    #[test]
+    fn nonce_sizes_match_the_cipher() {
+        assert_eq!(CipherKind::Aes256Gcm.cipher().generate_nonce().len(), 12);
+        assert_eq!(
+            CipherKind::XChaCha20Poly1305
+                .cipher()
+                .generate_nonce()
+                .len(),
+            24
+        );
+
+        let key = generate_data_key();
+        let mut sealed = seal(CipherKind::XChaCha20Poly1305, &key, b"secret", &[]).unwrap();
+        sealed.cipher = CipherKind::Aes256Gcm;
+        assert!(matches!(open(&key, &sealed, &[]), Err(VaultError::Aead)));
+    }
+
+    #[test]
+    fn vault_round_trips_with_every_cipher() {
+        let dir = tempfile::tempdir().unwrap();
+        for kind in CIPHERS {
+            let mut vault = Vault::new(&dir.path().join(format!("{kind:?}.vault")));
+            vault.encryption.cipher = kind;
+            let key = vault.initialize(b"master", Argon2Params::fast()).unwrap();
+            vault
+                .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+                .unwrap();
+
+            let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+            assert_eq!(reopened.encryption.cipher, kind);
+            assert_eq!(reopened.key_slots[0].wrapped_key.cipher, kind);
+            assert_eq!(reopened.unlock_and_get_key(b"master").unwrap(), key);
+            assert_eq!(
+                reopened.get_entry(&key, "github").unwrap().password,
+                "hunter2"
+            );
+        }
+    }
+}
@@ -1,48 +1,309 @@
-use crate::{errors::VaultError, models::Vault};
-use aes_gcm::{
-    Aes256Gcm,
-    aead::{Aead, AeadCore, KeyInit},
+use crate::{
+    backup::BackupPolicy,
+    cipher::CipherKind,
+    errors::VaultError,
+    kdf::KdfPolicy,
+    models::{Argon2Params, EncryptionData, KeySlotKind, Vault},
 };
 use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{Engine as _, engine::general_purpose::STANDARD};
-impl Vault {
-    pub fn derive_vault_key(&self, master_password: &[u8]) -> Result<[u8; 32], VaultError> {
-        let argon2_params = &self.argon2;
-        let params = Params::new(
-            argon2_params.mem_cost,
-            argon2_params.time_cost,
-            argon2_params.parallelism,
-            Some(32),
-        )?;
+use serde::Serialize;
+use zeroize::Zeroizing;
+
+pub(crate) const KEY_CHECK_PLAINTEXT: &[u8] = b"vpassword-key-check";
+
+/// The first version whose header covers the key slots and the cipher.
+const KEY_SLOT_HEADER_VERSION: u8 = 12;
+
+/// The cleartext part of the vault that gets bound to the ciphertext as
+/// associated data. Field order is fixed so the serialization is canonical,
+/// and fields added later are left out for older versions so their headers
+/// stay byte for byte what they were written with.
+#[derive(Serialize)]
+struct VaultHeader<'a> {
+    name: &'a str,
+    version: u8,
+    argon2: Option<&'a Argon2Params>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf_policy: Option<&'a KdfPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_policy: Option<&'a BackupPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cipher: Option<CipherKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_slots: Option<Vec<KeySlotHeader<'a>>>,
+}
+
+/// What of a key slot decides how hard its secret is to guess. The wrapped
+/// key itself is authenticated by its own seal.
+#[derive(Serialize)]
+struct KeySlotHeader<'a> {
+    kind: KeySlotKind,
+    argon2: &'a Argon2Params,
+    cipher: CipherKind,
+}
+
+impl Argon2Params {
+    pub fn derive_key(&self, secret: &[u8]) -> Result<[u8; 32], VaultError> {
+        let params = Params::new(self.mem_cost, self.time_cost, self.parallelism, Some(32))?;
         let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
 
         let mut output_key = [0u8; 32];
-        argon2.hash_password_into(
-            master_password,
-            &STANDARD.decode(&argon2_params.salt)?,
-            &mut output_key,
-        )?;
+        argon2.hash_password_into(secret, &STANDARD.decode(&self.salt)?, &mut output_key)?;
 
         Ok(output_key)
     }
+}
 
-    pub fn encrypt_data(&mut self, vault_key: &[u8], plaintext: &[u8]) -> Result<(), VaultError> {
-        let cipher = Aes256Gcm::new(vault_key.into());
-        let nonce = Aes256Gcm::generate_nonce(&mut aes_gcm::aead::OsRng);
+impl Vault {
+    /// Derives the key that encrypted the data directly before envelope encryption (versions 1 and 2).
+    pub fn derive_vault_key(&self, master_password: &[u8]) -> Result<[u8; 32], VaultError> {
+        self.argon2
+            .as_ref()
+            .ok_or(VaultError::NoSuchKeySlot(KeySlotKind::MasterPassword))?
+            .derive_key(master_password)
+    }
+
+    pub fn header_bytes(&self) -> Result<Vec<u8>, VaultError> {
+        let covers_key_slots = self.version >= KEY_SLOT_HEADER_VERSION;
+        let header = VaultHeader {
+            name: &self.name,
+            version: self.version,
+            argon2: self.argon2.as_ref(),
+            kdf_policy: self.kdf_policy.as_ref(),
+            backup_policy: self.backup_policy.as_ref(),
+            cipher: covers_key_slots.then_some(self.encryption.cipher),
+            key_slots: covers_key_slots.then(|| {
+                self.key_slots
+                    .iter()
+                    .map(|slot| KeySlotHeader {
+                        kind: slot.kind,
+                        argon2: &slot.argon2,
+                        cipher: slot.wrapped_key.cipher,
+                    })
+                    .collect()
+            }),
+        };
+        Ok(serde_json::to_vec(&header)?)
+    }
 
-        let ciphertext = cipher.encrypt(&nonce, plaintext.as_ref())?;
+    /// Applies `change` to the header and re-encrypts the data so it is bound
+    /// to the changed header. Does not save.
+    pub fn change_header<F>(&mut self, data_key: &[u8], change: F) -> Result<(), VaultError>
+    where
+        F: FnOnce(&mut Vault) -> Result<(), VaultError>,
+    {
+        let plaintext = Zeroizing::new(self.decrypt_data(data_key)?);
+        change(self)?;
+        self.encrypt_data(data_key, &plaintext)
+    }
 
-        self.encryption.nonce = STANDARD.encode(nonce);
-        self.encryption.ciphertext = STANDARD.encode(ciphertext);
+    pub fn encrypt_data(&mut self, vault_key: &[u8], plaintext: &[u8]) -> Result<(), VaultError> {
+        let cipher = self.encryption.cipher;
+        let header = self.header_bytes()?;
+        self.key_check = Some(seal(cipher, vault_key, KEY_CHECK_PLAINTEXT, &[])?);
+        self.header_check = Some(seal(cipher, vault_key, &[], &header)?);
+        self.encryption = seal(cipher, vault_key, plaintext, &header)?;
         Ok(())
     }
 
     pub fn decrypt_data(&self, vault_key: &[u8]) -> Result<Vec<u8>, VaultError> {
-        let cipher = Aes256Gcm::new(vault_key.into());
-        let nonce = STANDARD.decode(&self.encryption.nonce)?;
-        let ciphertext = STANDARD.decode(&self.encryption.ciphertext)?;
-        let decrypted_text = cipher.decrypt(nonce.as_slice().into(), ciphertext.as_ref())?;
+        if self.version < 2 {
+            // version 1 never had a key check, so finding one means the version was rolled back
+            if self.key_check.is_some() {
+                return Err(VaultError::HeaderTampered);
+            }
+            return open(vault_key, &self.encryption, &[]);
+        }
+
+        // the key check is not bound to the header, so passing it proves the key is right
+        let key_check = self.key_check.as_ref().ok_or(VaultError::HeaderTampered)?;
+        open(vault_key, key_check, &[])?;
+        // the data fails to open both when it is damaged and when the header was
+        // modified, the header check only fails in the second case
+        let header = self.header_bytes()?;
+        open(vault_key, &self.encryption, &header).map_err(|_| match &self.header_check {
+            Some(header_check) if open(vault_key, header_check, &header).is_err() => {
+                VaultError::HeaderTampered
+            }
+            Some(_) => VaultError::Corrupted("the vault data".to_string()),
+            None => VaultError::Corrupted("the vault data or its header".to_string()),
+        })
+    }
+}
+
+pub(crate) fn seal(
+    cipher: CipherKind,
+    key: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<EncryptionData, VaultError> {
+    let nonce = cipher.cipher().generate_nonce();
+    let ciphertext = cipher.cipher().encrypt(key, &nonce, plaintext, aad)?;
+
+    Ok(EncryptionData {
+        cipher,
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+pub(crate) fn open(key: &[u8], data: &EncryptionData, aad: &[u8]) -> Result<Vec<u8>, VaultError> {
+    let nonce = STANDARD.decode(&data.nonce)?;
+    let ciphertext = STANDARD.decode(&data.ciphertext)?;
+    let decrypted_text = data
+        .cipher
+        .cipher()
+        .decrypt(key, &nonce, &ciphertext, aad)?;
+
+    Ok(decrypted_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyslot::generate_data_key;
+    use std::path::PathBuf;
+
+    fn test_vault() -> (Vault, [u8; 32]) {
+        let mut vault = Vault::new(&PathBuf::from("test.vault"));
+        let key = generate_data_key();
+        vault
+            .add_key_slot(
+                &key,
+                KeySlotKind::MasterPassword,
+                b"master",
+                Argon2Params::fast(),
+            )
+            .unwrap();
+        vault.encrypt_data(&key, b"secret").unwrap();
+        (vault, key)
+    }
+
+    #[test]
+    fn tampered_header_is_detected() {
+        let (vault, key) = test_vault();
+        assert_eq!(vault.decrypt_data(&key).unwrap(), b"secret");
+
+        let mut renamed = vault.clone();
+        renamed.name = "other".to_string();
+        assert!(matches!(
+            renamed.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+
+        let mut downgraded = vault.clone();
+        downgraded.version = 1;
+        assert!(matches!(
+            downgraded.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+    }
+
+    #[test]
+    fn damaged_data_is_not_reported_as_tampering() {
+        let (mut vault, key) = test_vault();
+        let mut ciphertext = STANDARD.decode(&vault.encryption.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        vault.encryption.ciphertext = STANDARD.encode(ciphertext);
+        assert!(matches!(
+            vault.decrypt_data(&key),
+            Err(VaultError::Corrupted(what)) if what == "the vault data"
+        ));
+
+        let mut renamed = vault.clone();
+        renamed.name = "other".to_string();
+        assert!(matches!(
+            renamed.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+
+        // files written before the header check can't tell the two apart
+        renamed.header_check = None;
+        assert!(matches!(
+            renamed.decrypt_data(&key),
+            Err(VaultError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn weakened_key_slot_is_detected() {
+        let (vault, key) = test_vault();
+
+        let mut weakened = vault.clone();
+        weakened.key_slots[0].argon2.mem_cost /= 2;
+        weakened.key_slots[0].argon2.time_cost = 1;
+        assert!(matches!(
+            weakened.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+
+        let mut relabelled = vault.clone();
+        relabelled.key_slots[0].kind = KeySlotKind::RecoveryKey;
+        assert!(matches!(
+            relabelled.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+
+        let mut changed = vault.clone();
+        changed
+            .change_header(&key, |vault| {
+                vault.add_key_slot(&key, KeySlotKind::Keyfile, b"keyfile", Argon2Params::fast())
+            })
+            .unwrap();
+        assert_eq!(changed.decrypt_data(&key).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn wrong_key_is_not_reported_as_tampering() {
+        let (vault, _) = test_vault();
+        assert!(matches!(
+            vault.decrypt_data(&[0u8; 32]),
+            Err(VaultError::Aead)
+        ));
+        assert!(matches!(
+            vault.unwrap_data_key(KeySlotKind::MasterPassword, b"wrong"),
+            Err(VaultError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn damaged_key_slot_is_not_reported_as_wrong_password() {
+        let (mut vault, key) = test_vault();
+        let wrapped_key = &mut vault.key_slots[0].wrapped_key;
+        let mut ciphertext = STANDARD.decode(&wrapped_key.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        wrapped_key.ciphertext = STANDARD.encode(ciphertext);
+
+        assert!(matches!(
+            vault.unwrap_data_key(KeySlotKind::MasterPassword, b"master"),
+            Err(VaultError::Corrupted(_))
+        ));
+        assert!(matches!(
+            vault.unwrap_data_key(KeySlotKind::MasterPassword, b"wrong"),
+            Err(VaultError::WrongPassword)
+        ));
+
+        // a slot from before key checks can only guess
+        vault.key_slots[0].key_check = None;
+        assert!(matches!(
+            vault.unwrap_data_key(KeySlotKind::MasterPassword, b"master"),
+            Err(VaultError::WrongPassword)
+        ));
 
-        Ok(decrypted_text)
+        vault
+            .add_key_slot(
+                &key,
+                KeySlotKind::MasterPassword,
+                b"master",
+                Argon2Params::fast(),
+            )
+            .unwrap();
+        assert_eq!(
+            vault
+                .unwrap_data_key(KeySlotKind::MasterPassword, b"master")
+                .unwrap(),
+            key
+        );
     }
 }
@@ -1,5 +1,9 @@
 use std::{io::Error, string::FromUtf8Error};
 
+use serde::{Deserialize, Serialize};
+
+use crate::models::KeySlotKind;
+
 #[derive(Debug, thiserror::Error)]
 pub enum VaultError {
     #[error("IO error: {0}")]
@@ -20,11 +24,75 @@ pub enum VaultError {
     #[error("AEAD encryption/decryption error")]
     Aead,
 
+    #[error("wrong password or key")]
+    WrongPassword,
+
+    /// The secret was right, but something it unlocks does not decrypt.
+    #[error("vault is corrupted: {0}")]
+    Corrupted(String),
+
+    #[error("vault header has been tampered with")]
+    HeaderTampered,
+
+    #[error("unsupported vault version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("no {0:?} key slot in this vault")]
+    NoSuchKeySlot(KeySlotKind),
+
+    #[error("cannot remove the last key slot")]
+    LastKeySlot,
+
     #[error("duplicate entry: {0}")]
     DuplicateEntry(String),
 
     #[error("no such entry: {0}")]
     NoSuchEntry(String),
+
+    #[error("message of {0} bytes exceeds the frame size limit")]
+    FrameTooLarge(usize),
+
+    #[error("unsafe socket directory: {0}")]
+    UnsafeSocketDir(String),
+
+    #[error("no such backup: {0}")]
+    NoSuchBackup(String),
+
+    #[error("no backup passes every check")]
+    NoHealthyBackup,
+
+    #[error("no such attachment: {0}")]
+    NoSuchAttachment(String),
+
+    #[error("attachment already exists: {0}")]
+    DuplicateAttachment(String),
+
+    #[error("attachments can be at most {0} bytes")]
+    AttachmentTooLarge(u64),
+
+    #[error("invalid otpauth URI: {0}")]
+    InvalidOtp(String),
+
+    #[error("entry has no one-time password: {0}")]
+    NoOtp(String),
+
+    #[error("invalid entry name: {0:?}")]
+    InvalidName(String),
+
+    #[error("folder already exists: {0}")]
+    DuplicateFolder(String),
+
+    #[error("no such folder: {0}")]
+    NoSuchFolder(String),
+
+    #[error("folder is not empty: {0}")]
+    FolderNotEmpty(String),
+
+    #[error("invalid folder move: {0}")]
+    InvalidFolderMove(String),
+
+    #[error("no password at history position {0}")]
+    NoSuchHistoryItem(usize),
 }
 
 impl From<argon2::Error> for VaultError {
@@ -38,3 +106,59 @@ impl From<aes_gcm::aead::Error> for VaultError {
         VaultError::Aead
     }
 }
+
+/// A failed request as the agent reports it. Unlike `VaultError` it can be
+/// serialized, and it keeps apart the cases a client may want to react to
+/// instead of only printing them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ResponseError {
+    #[error("wrong password or key")]
+    WrongPassword,
+
+    #[error("vault is corrupted: {0}")]
+    Corrupted(String),
+
+    #[error("no vault is open")]
+    Locked,
+
+    #[error("a vault is already open")]
+    AlreadyOpen,
+
+    #[error("session timed out, you need to run open again")]
+    SessionExpired,
+
+    #[error("request was cancelled")]
+    Cancelled,
+
+    /// The agent refused the connection, sent instead of any other response.
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error(
+        "the agent speaks protocol versions {agent_min} to {agent}, the client {client_min} to {client}"
+    )]
+    IncompatibleProtocol {
+        agent: u32,
+        agent_min: u32,
+        client: u32,
+        client_min: u32,
+    },
+
+    #[error("no such entry: {0}")]
+    NoSuchEntry(String),
+
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<VaultError> for ResponseError {
+    fn from(e: VaultError) -> Self {
+        match e {
+            VaultError::WrongPassword => ResponseError::WrongPassword,
+            VaultError::Corrupted(what) => ResponseError::Corrupted(what),
+            VaultError::HeaderTampered => ResponseError::Corrupted(e.to_string()),
+            VaultError::NoSuchEntry(entry) => ResponseError::NoSuchEntry(entry),
+            e => ResponseError::Other(e.to_string()),
+        }
+    }
+}
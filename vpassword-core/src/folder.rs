@@ -0,0 +1,302 @@
+use std::collections::{BTreeSet, HashSet};
+
+use crate::{
+    errors::VaultError,
+    models::{PasswordEntry, PasswordList, Vault},
+    vault::unix_timestamp,
+};
+
+/// Turns ` /work//aws/ ` into `work/aws`. The root folder is the empty string.
+pub fn normalize_folder(path: &str) -> String {
+    path.split('/')
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Splits `work/aws/github` into its folder and name.
+pub fn split_path(path: &str) -> (String, String) {
+    let path = normalize_folder(path);
+    match path.rsplit_once('/') {
+        Some((folder, name)) => (folder.to_string(), name.to_string()),
+        None => (String::new(), path),
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), VaultError> {
+    if name.trim().is_empty() || name.contains('/') {
+        return Err(VaultError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Whether `folder` is `ancestor` or below it. Every folder is within the root.
+pub(crate) fn is_within(folder: &str, ancestor: &str) -> bool {
+    ancestor.is_empty()
+        || folder == ancestor
+        || folder
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn ancestors(folder: &str) -> impl Iterator<Item = &str> {
+    folder
+        .match_indices('/')
+        .map(|(index, _)| &folder[..index])
+        .chain((!folder.is_empty()).then_some(folder))
+}
+
+impl PasswordEntry {
+    pub fn path(&self) -> String {
+        if self.folder.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.folder, self.name)
+        }
+    }
+}
+
+impl PasswordList {
+    pub fn contains(&self, folder: &str, name: &str) -> bool {
+        self.passwords
+            .iter()
+            .any(|entry| entry.folder == folder && entry.name == name)
+    }
+
+    /// Every folder, whether created explicitly or implied by an entry, including all ancestors.
+    pub fn all_folders(&self) -> BTreeSet<String> {
+        self.folders
+            .iter()
+            .chain(self.passwords.iter().map(|entry| &entry.folder))
+            .flat_map(|folder| ancestors(folder))
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub fn create_folder(&mut self, path: &str) -> Result<(), VaultError> {
+        let path = normalize_folder(path);
+        if path.is_empty() || self.all_folders().contains(&path) {
+            return Err(VaultError::DuplicateFolder(path));
+        }
+        self.folders.push(path);
+        Ok(())
+    }
+
+    /// Moves (or renames) a folder together with everything inside it.
+    pub fn move_folder(&mut self, from: &str, to: &str) -> Result<(), VaultError> {
+        let (from, to) = (normalize_folder(from), normalize_folder(to));
+        if from.is_empty() || !self.all_folders().contains(&from) {
+            return Err(VaultError::NoSuchFolder(from));
+        }
+        // the root always exists, so moving into it would merge with it
+        if to.is_empty() {
+            return Err(VaultError::InvalidFolderMove(format!(
+                "{from} cannot become the top level"
+            )));
+        }
+        if is_within(&to, &from) || self.all_folders().contains(&to) {
+            return Err(VaultError::DuplicateFolder(to));
+        }
+
+        let rebased = |folder: &str| match folder.strip_prefix(from.as_str()) {
+            Some(rest) if is_within(folder, &from) => format!("{to}{rest}"),
+            _ => folder.to_string(),
+        };
+        let mut paths = HashSet::new();
+        for entry in &self.passwords {
+            let folder = rebased(&entry.folder);
+            if !paths.insert((folder.clone(), entry.name.as_str())) {
+                return Err(VaultError::DuplicateEntry(format!(
+                    "{folder}/{}",
+                    entry.name
+                )));
+            }
+        }
+
+        let rebase = |folder: &mut String| *folder = rebased(folder);
+        self.folders.iter_mut().for_each(rebase);
+        self.passwords
+            .iter_mut()
+            .for_each(|entry| rebase(&mut entry.folder));
+        // so restoring puts entries back where their folder went
+        self.trash
+            .iter_mut()
+            .for_each(|trashed| rebase(&mut trashed.entry.folder));
+        Ok(())
+    }
+
+    /// Only empty folders can be deleted, entries have to be moved or removed first.
+    pub fn delete_folder(&mut self, path: &str) -> Result<(), VaultError> {
+        let path = normalize_folder(path);
+        if path.is_empty() || !self.all_folders().contains(&path) {
+            return Err(VaultError::NoSuchFolder(path));
+        }
+        let not_empty = self
+            .all_folders()
+            .iter()
+            .any(|folder| folder != &path && is_within(folder, &path))
+            || self
+                .passwords
+                .iter()
+                .any(|entry| is_within(&entry.folder, &path));
+        if not_empty {
+            return Err(VaultError::FolderNotEmpty(path));
+        }
+
+        self.folders.retain(|folder| folder != &path);
+        Ok(())
+    }
+
+    pub fn move_entry(&mut self, id_or_path: &str, folder: &str) -> Result<(), VaultError> {
+        let folder = normalize_folder(folder);
+        let index = self
+            .position(id_or_path)
+            .ok_or_else(|| VaultError::NoSuchEntry(id_or_path.to_string()))?;
+        if self.passwords[index].folder == folder {
+            return Ok(());
+        }
+        let name = self.passwords[index].name.clone();
+        if self.contains(&folder, &name) {
+            return Err(VaultError::DuplicateEntry(format!("{folder}/{name}")));
+        }
+
+        let entry = &mut self.passwords[index];
+        entry.folder = folder;
+        entry.modified_at = unix_timestamp();
+        Ok(())
+    }
+}
+
+impl Vault {
+    pub fn create_folder(&mut self, vault_key: &[u8], path: &str) -> Result<(), VaultError> {
+        self.update_list(vault_key, |list| list.create_folder(path))
+    }
+
+    pub fn move_folder(
+        &mut self,
+        vault_key: &[u8],
+        from: &str,
+        to: &str,
+    ) -> Result<(), VaultError> {
+        self.update_list(vault_key, |list| list.move_folder(from, to))
+    }
+
+    pub fn delete_folder(&mut self, vault_key: &[u8], path: &str) -> Result<(), VaultError> {
+        self.update_list(vault_key, |list| list.delete_folder(path))
+    }
+
+    pub fn move_entry(
+        &mut self,
+        vault_key: &[u8],
+        id_or_path: &str,
+        folder: &str,
+    ) -> Result<(), VaultError> {
+        self.update_list(vault_key, |list| list.move_entry(id_or_path, folder))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> PasswordEntry {
+        let (folder, name) = split_path(path);
+        let mut entry = PasswordEntry::new(&name, "john", "hunter2");
+        entry.folder = folder;
+        entry
+    }
+
+    #[test]
+    fn addresses_entries_by_path() {
+        let list = PasswordList {
+            passwords: vec![
+                entry("github"),
+                entry("work/github"),
+                entry("work/aws/prod/console"),
+            ],
+            ..PasswordList::default()
+        };
+        assert_eq!(list.position("github"), Some(0));
+        assert_eq!(list.position("/work/github"), Some(1));
+        assert_eq!(list.position("work/aws/prod/console"), Some(2));
+        // unambiguous bare names are still found wherever they live
+        assert_eq!(list.position("console"), Some(2));
+        assert_eq!(list.position(&list.passwords[1].id.to_string()), Some(1));
+        assert_eq!(list.position("work/console"), None);
+    }
+
+    #[test]
+    fn folders_can_be_created_moved_and_deleted() {
+        let mut list = PasswordList {
+            passwords: vec![entry("work/aws/prod/console")],
+            ..PasswordList::default()
+        };
+        list.create_folder("personal/banking").unwrap();
+        assert!(matches!(
+            list.create_folder("work/aws"),
+            Err(VaultError::DuplicateFolder(_))
+        ));
+        assert_eq!(
+            list.all_folders().into_iter().collect::<Vec<_>>(),
+            [
+                "personal",
+                "personal/banking",
+                "work",
+                "work/aws",
+                "work/aws/prod"
+            ]
+        );
+
+        list.move_folder("work/aws", "cloud/aws").unwrap();
+        assert_eq!(list.passwords[0].path(), "cloud/aws/prod/console");
+        assert!(matches!(
+            list.move_folder("cloud", "cloud/aws/prod/nested"),
+            Err(VaultError::DuplicateFolder(_))
+        ));
+
+        let modified_at = list.passwords[0].modified_at - 1;
+        list.passwords[0].modified_at = modified_at;
+        list.move_entry("console", "cloud/aws/prod").unwrap();
+        assert_eq!(list.passwords[0].modified_at, modified_at);
+
+        assert!(matches!(
+            list.delete_folder("cloud/aws"),
+            Err(VaultError::FolderNotEmpty(_))
+        ));
+        list.move_entry("console", "").unwrap();
+        list.delete_folder("personal/banking").unwrap();
+        assert!(!list.all_folders().contains("personal/banking"));
+        assert!(matches!(
+            list.delete_folder("work"),
+            Err(VaultError::NoSuchFolder(_))
+        ));
+    }
+
+    #[test]
+    fn folders_cannot_be_moved_into_the_root() {
+        let mut list = PasswordList {
+            passwords: vec![
+                entry("github"),
+                entry("work/github"),
+                entry("work/aws/console"),
+            ],
+            ..PasswordList::default()
+        };
+        for to in ["", "/", " "] {
+            assert!(matches!(
+                list.move_folder("work", to),
+                Err(VaultError::InvalidFolderMove(_))
+            ));
+        }
+        assert!(matches!(
+            list.move_folder("work/aws", ""),
+            Err(VaultError::InvalidFolderMove(_))
+        ));
+        let paths: Vec<_> = list.passwords.iter().map(PasswordEntry::path).collect();
+        assert_eq!(paths, ["github", "work/github", "work/aws/console"]);
+
+        list.move_folder("work/aws", "aws").unwrap();
+        assert_eq!(list.passwords[2].path(), "aws/console");
+    }
+}
@@ -0,0 +1,216 @@
+use std::time::{Duration, Instant};
+
+use argon2::Params;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{
+    errors::VaultError,
+    models::{Argon2Params, KeySlotKind, Vault},
+};
+
+/// The weakest Argon2 parameters a vault accepts for its key slots.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KdfPolicy {
+    pub min_mem_cost: u32,
+    pub min_time_cost: u32,
+    pub min_parallelism: u32,
+}
+
+impl Default for KdfPolicy {
+    fn default() -> Self {
+        Self::from(&Argon2Params::default())
+    }
+}
+
+impl From<&Argon2Params> for KdfPolicy {
+    fn from(params: &Argon2Params) -> Self {
+        Self {
+            min_mem_cost: params.mem_cost,
+            min_time_cost: params.time_cost,
+            min_parallelism: params.parallelism,
+        }
+    }
+}
+
+impl Argon2Params {
+    /// Picks the time cost that makes one derivation on this machine take
+    /// roughly `target`, keeping the default memory cost.
+    pub fn calibrate(target: Duration) -> Result<Argon2Params, VaultError> {
+        let mut params = Argon2Params {
+            time_cost: 1,
+            ..Argon2Params::default()
+        };
+        let started = Instant::now();
+        params.derive_key(b"vpassword-calibration")?;
+        let single_pass = started.elapsed().as_secs_f64().max(f64::EPSILON);
+
+        params.time_cost = (target.as_secs_f64() / single_pass).round().max(1.0) as u32;
+        Ok(params)
+    }
+
+    pub fn meets(&self, policy: &KdfPolicy) -> bool {
+        self.mem_cost >= policy.min_mem_cost
+            && self.time_cost >= policy.min_time_cost
+            && self.parallelism >= policy.min_parallelism
+    }
+
+    fn raised_to(&self, policy: &KdfPolicy) -> Argon2Params {
+        Argon2Params {
+            mem_cost: self.mem_cost.max(policy.min_mem_cost),
+            time_cost: self.time_cost.max(policy.min_time_cost),
+            parallelism: self.parallelism.max(policy.min_parallelism),
+            ..self.with_fresh_salt()
+        }
+    }
+}
+
+impl Vault {
+    pub fn kdf_policy(&self) -> KdfPolicy {
+        self.kdf_policy.clone().unwrap_or_default()
+    }
+
+    /// Parameters for a new key slot: the defaults, raised to the vault's policy.
+    pub fn key_slot_params(&self) -> Argon2Params {
+        Argon2Params::default().raised_to(&self.kdf_policy())
+    }
+
+    /// The policy is part of the authenticated header, so changing it re-encrypts
+    /// the data. Key slots below it are upgraded the next time they unlock the vault.
+    pub fn set_kdf_policy(&mut self, data_key: &[u8], policy: KdfPolicy) -> Result<(), VaultError> {
+        // slots get raised to at least the policy, so it has to be usable itself
+        Params::new(
+            policy.min_mem_cost,
+            policy.min_time_cost,
+            policy.min_parallelism,
+            Some(32),
+        )?;
+        let plaintext = Zeroizing::new(self.decrypt_data(data_key)?);
+        self.kdf_policy = Some(policy);
+        self.encrypt_data(data_key, &plaintext)?;
+        self.save_to_file()
+    }
+
+    /// Re-wraps the data key in the `kind` slot with parameters that satisfy the
+    /// vault's policy, if the stored ones fall below it or the slot predates key
+    /// checks. Returns whether it did.
+    pub fn upgrade_key_slot(
+        &mut self,
+        data_key: &[u8],
+        kind: KeySlotKind,
+        secret: &[u8],
+    ) -> Result<bool, VaultError> {
+        let policy = self.kdf_policy();
+        let slot = self.key_slot(kind)?;
+        if slot.argon2.meets(&policy) && slot.key_check.is_some() {
+            return Ok(false);
+        }
+        let argon2 = slot.argon2.raised_to(&policy);
+
+        // cheap by definition, and makes sure we never wrap under a mistyped secret
+        self.unwrap_data_key(kind, secret)?;
+        self.change_header(data_key, |vault| {
+            vault.add_key_slot(data_key, kind, secret, argon2)
+        })?;
+        self.save_to_file()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_key_slot_is_upgraded_to_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::new(&dir.path().join("test.vault"));
+        let key = vault.initialize(b"master", Argon2Params::fast()).unwrap();
+        assert!(
+            !vault
+                .upgrade_key_slot(&key, KeySlotKind::MasterPassword, b"master")
+                .unwrap()
+        );
+
+        vault
+            .set_kdf_policy(
+                &key,
+                KdfPolicy {
+                    min_mem_cost: 16,
+                    min_time_cost: 2,
+                    min_parallelism: 1,
+                },
+            )
+            .unwrap();
+        assert!(
+            vault
+                .upgrade_key_slot(&key, KeySlotKind::MasterPassword, b"wrong")
+                .is_err()
+        );
+        assert!(
+            vault
+                .upgrade_key_slot(&key, KeySlotKind::MasterPassword, b"master")
+                .unwrap()
+        );
+
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        let argon2 = &reopened
+            .key_slot(KeySlotKind::MasterPassword)
+            .unwrap()
+            .argon2;
+        assert_eq!((argon2.mem_cost, argon2.time_cost), (16, 2));
+        assert_eq!(reopened.unlock_and_get_key(b"master").unwrap(), key);
+    }
+
+    #[test]
+    fn kdf_policy_is_validated_and_bound_to_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::new(&dir.path().join("test.vault"));
+        let key = vault.initialize(b"master", Argon2Params::fast()).unwrap();
+        let unusable = KdfPolicy {
+            min_mem_cost: 8,
+            min_time_cost: 0,
+            min_parallelism: 1,
+        };
+        assert!(vault.set_kdf_policy(&key, unusable).is_err());
+
+        let policy = KdfPolicy {
+            min_mem_cost: 32,
+            min_time_cost: 8,
+            min_parallelism: 1,
+        };
+        vault.set_kdf_policy(&key, policy.clone()).unwrap();
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert_eq!(reopened.kdf_policy(), policy);
+        // new slots start out meeting it instead of waiting for an upgrade
+        assert_eq!(reopened.key_slot_params().time_cost, 8);
+        assert!(reopened.decrypt_data(&key).is_ok());
+
+        // lowering the policy would stop weak slots from being upgraded
+        reopened.kdf_policy = Some(KdfPolicy::from(&Argon2Params::fast()));
+        assert!(matches!(
+            reopened.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+        reopened.kdf_policy = None;
+        assert!(matches!(
+            reopened.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+    }
+
+    #[test]
+    fn key_slot_without_key_check_is_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::new(&dir.path().join("test.vault"));
+        let key = vault.initialize(b"master", Argon2Params::fast()).unwrap();
+        vault.key_slots[0].key_check = None;
+
+        assert!(
+            vault
+                .upgrade_key_slot(&key, KeySlotKind::MasterPassword, b"master")
+                .unwrap()
+        );
+        assert!(vault.key_slots[0].key_check.is_some());
+    }
+}
@@ -0,0 +1,91 @@
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::rand_core::{OsRng, TryRngCore};
+use zeroize::Zeroizing;
+
+use crate::{
+    encryption::{KEY_CHECK_PLAINTEXT, open, seal},
+    errors::VaultError,
+    models::{Argon2Params, KeySlot, KeySlotKind, Vault},
+};
+
+pub fn generate_data_key() -> [u8; 32] {
+    let mut data_key = [0u8; 32];
+    OsRng.try_fill_bytes(&mut data_key).unwrap();
+    data_key
+}
+
+/// A random secret meant to be written down, unlocking the vault through a
+/// `RecoveryKey` slot when the master password is lost.
+pub fn generate_recovery_key() -> String {
+    let mut recovery_key = Zeroizing::new([0u8; 32]);
+    OsRng.try_fill_bytes(recovery_key.as_mut()).unwrap();
+    URL_SAFE_NO_PAD.encode(recovery_key.as_ref())
+}
+
+impl Vault {
+    pub fn key_slot(&self, kind: KeySlotKind) -> Result<&KeySlot, VaultError> {
+        self.key_slots
+            .iter()
+            .find(|slot| slot.kind == kind)
+            .ok_or(VaultError::NoSuchKeySlot(kind))
+    }
+
+    /// Wraps `data_key` under `secret`, replacing any existing slot of the same kind.
+    pub fn add_key_slot(
+        &mut self,
+        data_key: &[u8],
+        kind: KeySlotKind,
+        secret: &[u8],
+        argon2: Argon2Params,
+    ) -> Result<(), VaultError> {
+        let cipher = self.encryption.cipher;
+        let wrapping_key = Zeroizing::new(argon2.derive_key(secret)?);
+        let slot = KeySlot {
+            kind,
+            wrapped_key: seal(cipher, wrapping_key.as_ref(), data_key, &[])?,
+            key_check: Some(seal(
+                cipher,
+                wrapping_key.as_ref(),
+                KEY_CHECK_PLAINTEXT,
+                &[],
+            )?),
+            argon2,
+        };
+
+        self.key_slots.retain(|slot| slot.kind != kind);
+        self.key_slots.push(slot);
+        Ok(())
+    }
+
+    pub fn remove_key_slot(&mut self, kind: KeySlotKind) -> Result<(), VaultError> {
+        self.key_slot(kind)?;
+        if self.key_slots.len() == 1 {
+            return Err(VaultError::LastKeySlot);
+        }
+        self.key_slots.retain(|slot| slot.kind != kind);
+        Ok(())
+    }
+
+    pub fn unwrap_data_key(
+        &self,
+        kind: KeySlotKind,
+        secret: &[u8],
+    ) -> Result<[u8; 32], VaultError> {
+        let slot = self.key_slot(kind)?;
+        let wrapping_key = Zeroizing::new(slot.argon2.derive_key(secret)?);
+        // a slot without a key check that fails to open most likely got a wrong secret
+        let right_secret = slot
+            .key_check
+            .as_ref()
+            .is_some_and(|key_check| open(wrapping_key.as_ref(), key_check, &[]).is_ok());
+        let damaged = || VaultError::Corrupted(format!("the {kind:?} key slot"));
+        let data_key = match open(wrapping_key.as_ref(), &slot.wrapped_key, &[]) {
+            Ok(data_key) => Zeroizing::new(data_key),
+            Err(VaultError::Aead) if right_secret => return Err(damaged()),
+            Err(VaultError::Aead) => return Err(VaultError::WrongPassword),
+            Err(e) => return Err(e),
+        };
+
+        data_key.as_slice().try_into().map_err(|_| damaged())
+    }
+}
@@ -1,4 +1,17 @@
+pub mod attachment;
+pub mod backup;
+pub mod check;
+pub mod cipher;
 pub mod encryption;
 pub mod errors;
+pub mod folder;
+pub mod kdf;
+pub mod keyslot;
+pub mod migration;
 pub mod models;
+pub mod otp;
+pub mod protocol;
+pub mod search;
+pub mod socket;
+pub mod trash;
 pub mod vault;
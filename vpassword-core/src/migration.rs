@@ -0,0 +1,317 @@
+use std::{fs, path::PathBuf};
+
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    errors::VaultError,
+    keyslot::generate_data_key,
+    models::{KeySlotKind, Vault},
+    trash::default_trash_retention_days,
+    vault::{VAULT_VERSION, damaged_key_check, unix_timestamp},
+};
+
+/// A single upgrade step taking a vault from version `from` to `from + 1`.
+pub struct Migration {
+    pub from: u8,
+    pub description: &'static str,
+    pub apply: fn(&mut MigrationContext) -> Result<(), VaultError>,
+}
+
+/// Everything a step may need to change: the cleartext header, the key
+/// (steps that change the KDF re-derive it from the master password) and the
+/// decrypted entry list as loose JSON so entry schema changes can be expressed.
+pub struct MigrationContext<'a> {
+    pub vault: &'a mut Vault,
+    pub master_password: &'a [u8],
+    pub vault_key: [u8; 32],
+    pub entries: serde_json::Value,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "bind the vault header to the ciphertext as associated data",
+        // nothing to transform, the final re-encryption writes the new format
+        apply: |_| Ok(()),
+    },
+    Migration {
+        from: 2,
+        description: "encrypt the data under a random data key wrapped by the master password",
+        apply: |context| {
+            let argon2 = context
+                .vault
+                .argon2
+                .take()
+                .ok_or(VaultError::NoSuchKeySlot(KeySlotKind::MasterPassword))?;
+            let data_key = generate_data_key();
+            context.vault.add_key_slot(
+                &data_key,
+                KeySlotKind::MasterPassword,
+                context.master_password,
+                argon2.with_fresh_salt(),
+            )?;
+            context.vault_key = data_key;
+            Ok(())
+        },
+    },
+    Migration {
+        from: 3,
+        description: "record which AEAD cipher sealed each encrypted blob",
+        // blobs without an identifier already read as AES-256-GCM
+        apply: |_| Ok(()),
+    },
+    Migration {
+        from: 4,
+        description: "add urls, notes, tags, custom fields and timestamps to entries",
+        apply: |context| {
+            let now = unix_timestamp();
+            for entry in entry_objects(&mut context.entries) {
+                entry.entry("urls").or_insert(json!([]));
+                entry.entry("notes").or_insert(json!(""));
+                entry.entry("tags").or_insert(json!([]));
+                entry.entry("custom_fields").or_insert(json!([]));
+                entry.entry("created_at").or_insert(json!(now));
+                entry.entry("modified_at").or_insert(json!(now));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 5,
+        description: "keep a history of previous passwords in every entry",
+        apply: |context| {
+            for entry in entry_objects(&mut context.entries) {
+                entry.entry("history").or_insert(json!([]));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 6,
+        description: "give every entry a stable ID",
+        apply: |context| {
+            for entry in entry_objects(&mut context.entries) {
+                entry.entry("id").or_insert_with(|| json!(Uuid::new_v4()));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 7,
+        description: "organise entries into folders",
+        apply: |context| {
+            for entry in entry_objects(&mut context.entries) {
+                entry.entry("folder").or_insert(json!(""));
+            }
+            if let Some(list) = context.entries.as_object_mut() {
+                list.entry("folders").or_insert(json!([]));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 8,
+        description: "add one-time passwords",
+        apply: |context| {
+            for entry in entry_objects(&mut context.entries) {
+                entry.entry("otp").or_insert(json!(null));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 9,
+        description: "attach encrypted files to entries",
+        apply: |context| {
+            for entry in entry_objects(&mut context.entries) {
+                entry.entry("attachments").or_insert(json!([]));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 10,
+        description: "keep removed entries in a trash",
+        apply: |context| {
+            if let Some(list) = context.entries.as_object_mut() {
+                list.entry("trash").or_insert(json!([]));
+                list.entry("trash_retention_days")
+                    .or_insert(json!(default_trash_retention_days()));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 11,
+        description: "bind the key slots and the cipher to the vault header",
+        // nothing to transform, the final re-encryption writes the new header
+        apply: |_| Ok(()),
+    },
+];
+
+fn entry_objects(
+    entries: &mut serde_json::Value,
+) -> impl Iterator<Item = &mut serde_json::Map<String, serde_json::Value>> {
+    entries
+        .get_mut("passwords")
+        .and_then(|passwords| passwords.as_array_mut())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.as_object_mut())
+}
+
+impl Vault {
+    pub fn needs_migration(&self) -> bool {
+        self.version < VAULT_VERSION
+    }
+
+    pub fn check_version(&self) -> Result<(), VaultError> {
+        if self.version == 0 || self.version > VAULT_VERSION {
+            return Err(VaultError::UnsupportedVersion(self.version));
+        }
+        Ok(())
+    }
+
+    pub fn backup_path(&self) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap_or_default().to_owned();
+        file_name.push(format!(".v{}.bak", self.version));
+        self.path.with_file_name(file_name)
+    }
+
+    /// Runs every registered step between the stored version and the current
+    /// one, keeping a copy of the original file next to it before saving.
+    pub fn migrate(&mut self, master_password: &[u8]) -> Result<[u8; 32], VaultError> {
+        self.check_version()?;
+        // before key slots the key comes straight from the password, and
+        // version 1 can't tell a wrong one from damage at all
+        let derived = self.version < 3;
+        let vault_key = if derived {
+            self.derive_vault_key(master_password)?
+        } else {
+            self.unwrap_data_key(KeySlotKind::MasterPassword, master_password)?
+        };
+        let plaintext = self.decrypt_data(&vault_key).map_err(|e| match e {
+            VaultError::Aead if derived => VaultError::WrongPassword,
+            VaultError::Aead => damaged_key_check(),
+            e => e,
+        })?;
+        let entries = serde_json::from_slice(&plaintext)?;
+        fs::copy(&self.path, self.backup_path())?;
+
+        let mut context = MigrationContext {
+            vault: self,
+            master_password,
+            vault_key,
+            entries,
+        };
+        while context.vault.version < VAULT_VERSION {
+            let version = context.vault.version;
+            let migration = MIGRATIONS
+                .iter()
+                .find(|migration| migration.from == version)
+                .ok_or(VaultError::UnsupportedVersion(version))?;
+            (migration.apply)(&mut context)?;
+            context.vault.version = version + 1;
+        }
+
+        let vault_key = context.vault_key;
+        let entries = serde_json::to_string(&context.entries)?;
+        self.encrypt_data(&vault_key, entries.as_bytes())?;
+        self.save_to_file()?;
+
+        Ok(vault_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cipher::CipherKind, encryption::seal, models::Argon2Params};
+
+    fn legacy_vault(dir: &std::path::Path) -> Vault {
+        let mut vault = Vault::new(&dir.join("legacy.vault"));
+        vault.argon2 = Some(Argon2Params::fast());
+        vault.version = 1;
+        let key = vault.derive_vault_key(b"master").unwrap();
+        let list = r#"{"passwords":[{"name":"github","username":"john","password":"hunter2"}]}"#;
+        vault.encryption = seal(CipherKind::Aes256Gcm, &key, list.as_bytes(), &[]).unwrap();
+        vault.save_to_file().unwrap();
+        vault
+    }
+
+    #[test]
+    fn migrates_version_1_and_keeps_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = legacy_vault(dir.path());
+        let backup_path = vault.backup_path();
+
+        let key = vault.unlock_and_get_key(b"master").unwrap();
+        assert_eq!(vault.version, VAULT_VERSION);
+        assert!(vault.argon2.is_none());
+
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert_eq!(reopened.version, VAULT_VERSION);
+        let entry = reopened.get_entry(&key, "github").unwrap();
+        assert_eq!(entry.password, "hunter2");
+        assert!(entry.urls.is_empty());
+        assert!(entry.created_at > 0);
+        assert!(!entry.id.is_nil());
+
+        let backup = Vault::new_from_file(&backup_path).unwrap();
+        assert_eq!(backup.version, 1);
+    }
+
+    #[test]
+    fn wrong_password_leaves_legacy_vault_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = legacy_vault(dir.path());
+
+        assert!(matches!(
+            vault.unlock_and_get_key(b"wrong"),
+            Err(VaultError::WrongPassword)
+        ));
+        assert!(!vault.backup_path().exists());
+        assert_eq!(Vault::new_from_file(&vault.path).unwrap().version, 1);
+    }
+
+    #[test]
+    fn migrates_key_slot_vault_to_bound_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::new(&dir.path().join("slots.vault"));
+        vault.version = 11;
+        let key = vault.initialize(b"master", Argon2Params::fast()).unwrap();
+        let mut weakened = vault.clone();
+        weakened.key_slots[0].argon2.time_cost += 1;
+        assert!(weakened.decrypt_data(&key).is_ok());
+
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert!(matches!(
+            reopened.unlock_and_get_key(b"wrong"),
+            Err(VaultError::WrongPassword)
+        ));
+        assert_eq!(reopened.unlock_and_get_key(b"master").unwrap(), key);
+
+        let mut migrated = Vault::new_from_file(&vault.path).unwrap();
+        assert_eq!(migrated.version, VAULT_VERSION);
+        assert!(migrated.decrypt_data(&key).is_ok());
+        migrated.key_slots[0].argon2.time_cost += 1;
+        assert!(matches!(
+            migrated.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+    }
+
+    #[test]
+    fn refuses_future_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = legacy_vault(dir.path());
+        vault.version = VAULT_VERSION + 1;
+        vault.save_to_file().unwrap();
+
+        assert!(matches!(
+            Vault::new_from_file(&vault.path),
+            Err(VaultError::UnsupportedVersion(_))
+        ));
+    }
+}
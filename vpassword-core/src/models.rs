@@ -3,46 +3,311 @@ use std::path::PathBuf;
 use base64::{Engine as _, engine::general_purpose::STANDARD};
 use rand::rand_core::{OsRng, TryRngCore};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
+use crate::{
+    backup::{BackupInfo, BackupPolicy},
+    cipher::CipherKind,
+    errors::ResponseError,
+    kdf::KdfPolicy,
+    otp::OtpCode,
+    protocol::Hello,
+    trash::default_trash_retention_days,
+};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Response {
     Ok,
-    Error(String),
-    PasswordEntry { entry: PasswordEntry },
-    PasswordList { list: PasswordList },
+    Error(ResponseError),
+    Hello {
+        hello: Hello,
+    },
+    PasswordEntry {
+        entry: Box<PasswordEntry>,
+    },
+    /// Every entry without its secrets, and every folder including empty ones.
+    EntryList {
+        entries: Vec<EntrySummary>,
+        folders: Vec<String>,
+    },
+    EntrySummaries {
+        entries: Vec<EntrySummary>,
+    },
+    OtpCode {
+        otp: OtpCode,
+    },
+    UploadStarted {
+        upload: Uuid,
+    },
+    TrashList {
+        entries: Vec<TrashSummary>,
+    },
+    BackupList {
+        backups: Vec<BackupInfo>,
+    },
+    Attachment {
+        attachment: Attachment,
+    },
+    AttachmentChunk {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+        /// Total size of the attachment, so the client knows when to stop asking.
+        size: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
+    /// Must come first on a connection, see `protocol::Hello`.
+    Hello {
+        hello: Hello,
+    },
     UnlockVault {
         vault_path: PathBuf,
         master_password: Vec<u8>,
     },
     LockVault,
 
+    UnlockVaultWithKeySlot {
+        vault_path: PathBuf,
+        kind: KeySlotKind,
+        secret: Vec<u8>,
+    },
+    AddKeySlot {
+        kind: KeySlotKind,
+        secret: Vec<u8>,
+    },
+    RemoveKeySlot {
+        kind: KeySlotKind,
+    },
+    ChangeMasterPassword {
+        old_password: Vec<u8>,
+        new_password: Vec<u8>,
+    },
+
     ListEntries,
     GetEntry {
-        name: String,
+        /// ID or name of the entry.
+        entry: String,
     },
     AddEntry {
         entry: PasswordEntry,
     },
+    ListBackups,
+    RestoreBackup {
+        backup: String,
+    },
+    SetBackupPolicy {
+        policy: BackupPolicy,
+    },
+    SetKdfPolicy {
+        policy: KdfPolicy,
+    },
+    ListTrash,
+    RestoreEntry {
+        entry: String,
+    },
+    /// Deletes one trashed entry for good, or the whole trash when `entry` is `None`.
+    PurgeTrash {
+        entry: Option<String>,
+    },
+    SetTrashRetention {
+        days: u32,
+    },
+    /// Moves the entry to the trash.
     RemoveEntry {
+        entry: String,
+    },
+    UpdateEntry {
+        entry: String,
+        update: EntryUpdate,
+    },
+    RenameEntry {
+        entry: String,
+        new_name: String,
+    },
+    MoveEntry {
+        entry: String,
+        folder: String,
+    },
+    CreateFolder {
+        path: String,
+    },
+    MoveFolder {
+        from: String,
+        to: String,
+    },
+    DeleteFolder {
+        path: String,
+    },
+    GetOtp {
+        entry: String,
+    },
+    /// Attachments are sent in chunks of at most `ATTACHMENT_TRANSFER_CHUNK`
+    /// bytes and only stored once the upload is finished.
+    StartAttachmentUpload {
+        entry: String,
         name: String,
+        size: u64,
+    },
+    UploadAttachmentChunk {
+        upload: Uuid,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    FinishAttachmentUpload {
+        upload: Uuid,
+    },
+    GetAttachmentChunk {
+        entry: String,
+        attachment: String,
+        offset: u64,
+    },
+    RemoveAttachment {
+        entry: String,
+        attachment: String,
+    },
+    SearchEntries {
+        query: String,
+        filters: SearchFilters,
+    },
+    RestorePassword {
+        entry: String,
+        /// Position in the entry's history, 0 being the most recent previous password.
+        index: usize,
     },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Zeroize, ZeroizeOnDrop)]
 pub struct PasswordEntry {
+    /// Assigned by the vault when the entry is added and never changed afterwards.
+    #[zeroize(skip)]
+    pub id: Uuid,
     pub name: String,
+    /// Slash separated path such as `work/aws/prod`, empty for the root.
+    #[serde(default)]
+    pub folder: String,
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub custom_fields: Vec<CustomField>,
+    /// `otpauth://` URI holding the TOTP or HOTP seed.
+    #[serde(default)]
+    pub otp: Option<String>,
+    /// Files stored encrypted next to the vault, see [`Vault::attachment_dir`].
+    #[serde(default)]
+    #[zeroize(skip)]
+    pub attachments: Vec<Attachment>,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub modified_at: u64,
+    /// Previous passwords, most recent first.
+    #[serde(default)]
+    pub history: Vec<PasswordHistoryItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct PasswordHistoryItem {
+    pub password: String,
+    /// When this password was replaced, in seconds since the Unix epoch.
+    pub changed_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub name: String,
+    /// Size of the decrypted data in bytes.
+    pub size: u64,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct CustomField {
+    pub name: String,
+    pub value: String,
+    /// Sensitive values are treated like passwords: prompted for and hidden from listings.
+    #[serde(default)]
+    pub sensitive: bool,
+}
+
+/// A partial change to an entry: `None` fields are left as they are, custom
+/// fields are upserted by name.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Zeroize, ZeroizeOnDrop)]
+pub struct EntryUpdate {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub urls: Option<Vec<String>>,
+    pub notes: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// An empty URI removes the one-time password.
+    pub otp: Option<String>,
+    pub custom_fields: Vec<CustomField>,
+    pub removed_fields: Vec<String>,
 }
 
+/// The non secret part of an entry, safe to hand out without unlocking a specific entry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntrySummary {
+    pub id: Uuid,
+    pub name: String,
+    pub folder: String,
+    pub username: String,
+    pub urls: Vec<String>,
+    pub tags: Vec<String>,
+    pub created_at: u64,
+    pub modified_at: u64,
+}
+
+/// Restricts a search; every filter that is set has to match. Comparisons ignore case.
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchFilters {
+    /// The entry has to carry all of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Substring of one of the entry's URLs.
+    pub url: Option<String>,
+    /// Substring of the username.
+    pub username: Option<String>,
+    /// Only entries in this folder or below it.
+    pub folder: Option<String>,
+    /// Also match entries whose name contains the query's characters in order.
+    #[serde(default)]
+    pub fuzzy: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PasswordList {
     pub passwords: Vec<PasswordEntry>,
+    /// Folders created explicitly, so they exist before anything is put in them.
+    #[serde(default)]
+    pub folders: Vec<String>,
+    /// Removed entries, restorable until they are older than `trash_retention_days`.
+    #[serde(default)]
+    pub trash: Vec<TrashedEntry>,
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashedEntry {
+    pub entry: PasswordEntry,
+    pub deleted_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashSummary {
+    pub entry: EntrySummary,
+    pub deleted_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -55,28 +320,113 @@ pub struct Argon2Params {
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct EncryptionData {
+    #[serde(default)]
+    pub cipher: CipherKind,
     pub nonce: String,
     pub ciphertext: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySlotKind {
+    MasterPassword,
+    Keyfile,
+    RecoveryKey,
+}
+
+/// The vault's random data key, wrapped under a key derived from one unlock secret.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeySlot {
+    pub kind: KeySlotKind,
+    pub argon2: Argon2Params,
+    pub wrapped_key: EncryptionData,
+    /// A known value sealed under the same key as `wrapped_key`, so a wrong
+    /// secret can be told apart from a damaged slot. Older slots lack it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<EncryptionData>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Vault {
     pub name: String,
     pub path: PathBuf,
     pub version: u8,
-    pub argon2: Argon2Params,
+    /// Only present in versions 1 and 2, where the password derived key encrypted the data directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argon2: Option<Argon2Params>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_slots: Vec<KeySlot>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf_policy: Option<KdfPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_policy: Option<BackupPolicy>,
     pub encryption: EncryptionData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<EncryptionData>,
+    /// Seals nothing but the header, so a failure to decrypt the data can be
+    /// blamed on either the header or the data. Missing in files written before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_check: Option<EncryptionData>,
+}
+
+impl Argon2Params {
+    pub fn with_fresh_salt(&self) -> Self {
+        Self {
+            salt: generate_salt(),
+            ..self.clone()
+        }
+    }
 }
 
 impl Default for Argon2Params {
     fn default() -> Self {
-        let mut salt = [0u8; 32];
-        OsRng.try_fill_bytes(&mut salt).unwrap();
         Self {
-            salt: STANDARD.encode(salt),
+            salt: generate_salt(),
             mem_cost: 64 * 1024,
             time_cost: 3,
             parallelism: 1,
         }
     }
 }
+
+impl Default for PasswordList {
+    fn default() -> Self {
+        Self {
+            passwords: Vec::new(),
+            folders: Vec::new(),
+            trash: Vec::new(),
+            trash_retention_days: default_trash_retention_days(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Argon2Params {
+    pub(crate) fn fast() -> Self {
+        Self {
+            mem_cost: 8,
+            time_cost: 1,
+            ..Self::default()
+        }
+    }
+}
+
+fn generate_salt() -> String {
+    let mut salt = [0u8; 32];
+    OsRng.try_fill_bytes(&mut salt).unwrap();
+    STANDARD.encode(salt)
+}
+
+/// Serializes binary data as base64 instead of serde's default array of numbers.
+mod base64_bytes {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,362 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use zeroize::Zeroizing;
+
+use crate::{errors::VaultError, models::Vault, vault::unix_timestamp};
+
+const DEFAULT_DIGITS: u32 = 6;
+const DEFAULT_PERIOD: u64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpKind {
+    /// RFC 6238, the counter is the number of `period`s since the Unix epoch.
+    Totp { period: u64 },
+    /// RFC 4226, the counter is stored and moves forward with every code.
+    Hotp { counter: u64 },
+}
+
+/// A parsed `otpauth://` URI.
+#[derive(Debug, Clone)]
+pub struct OtpConfig {
+    pub kind: OtpKind,
+    /// `issuer:account`, kept percent encoded as it appeared in the URI.
+    pub label: String,
+    pub secret: Zeroizing<Vec<u8>>,
+    pub algorithm: OtpAlgorithm,
+    pub digits: u32,
+    /// Other query parameters (e.g. `issuer`), kept as they were so the URI round trips.
+    pub extra_params: Vec<(String, String)>,
+}
+
+/// The current code of an entry and, for TOTP, how many seconds it stays valid.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OtpCode {
+    pub code: String,
+    pub valid_for: Option<u64>,
+}
+
+impl OtpConfig {
+    pub fn parse(uri: &str) -> Result<Self, VaultError> {
+        let invalid = |reason: &str| VaultError::InvalidOtp(reason.to_string());
+
+        let rest = uri
+            .trim()
+            .strip_prefix("otpauth://")
+            .ok_or_else(|| invalid("expected an otpauth:// URI"))?;
+        let (kind, rest) = rest
+            .split_once('/')
+            .ok_or_else(|| invalid("missing label"))?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut secret = None;
+        let mut algorithm = OtpAlgorithm::Sha1;
+        let mut digits = DEFAULT_DIGITS;
+        let mut period = DEFAULT_PERIOD;
+        let mut counter = None;
+        let mut extra_params = Vec::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key.to_ascii_lowercase().as_str() {
+                "secret" => {
+                    secret =
+                        Some(base32_decode(value).ok_or_else(|| invalid("secret is not base32"))?)
+                }
+                "algorithm" => {
+                    algorithm = match value.to_ascii_uppercase().as_str() {
+                        "SHA1" => OtpAlgorithm::Sha1,
+                        "SHA256" => OtpAlgorithm::Sha256,
+                        "SHA512" => OtpAlgorithm::Sha512,
+                        _ => return Err(invalid("unsupported algorithm")),
+                    }
+                }
+                "digits" => {
+                    digits = value
+                        .parse()
+                        .ok()
+                        .filter(|digits| (6..=10).contains(digits))
+                        .ok_or_else(|| invalid("digits must be between 6 and 10"))?
+                }
+                "period" => {
+                    period = value
+                        .parse()
+                        .ok()
+                        .filter(|period| *period > 0)
+                        .ok_or_else(|| invalid("invalid period"))?
+                }
+                "counter" => counter = Some(value.parse().map_err(|_| invalid("invalid counter"))?),
+                _ => extra_params.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        let kind = match kind.to_ascii_lowercase().as_str() {
+            "totp" => OtpKind::Totp { period },
+            "hotp" => OtpKind::Hotp {
+                counter: counter.ok_or_else(|| invalid("HOTP needs a counter"))?,
+            },
+            _ => return Err(invalid("expected totp or hotp")),
+        };
+        let secret = secret
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| invalid("missing secret"))?;
+
+        Ok(OtpConfig {
+            kind,
+            label: label.to_string(),
+            secret: Zeroizing::new(secret),
+            algorithm,
+            digits,
+            extra_params,
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        let (kind, counter_param) = match self.kind {
+            OtpKind::Totp { period } => ("totp", format!("period={period}")),
+            OtpKind::Hotp { counter } => ("hotp", format!("counter={counter}")),
+        };
+        let algorithm = match self.algorithm {
+            OtpAlgorithm::Sha1 => "SHA1",
+            OtpAlgorithm::Sha256 => "SHA256",
+            OtpAlgorithm::Sha512 => "SHA512",
+        };
+        let mut uri = format!(
+            "otpauth://{kind}/{}?secret={}&algorithm={algorithm}&digits={}&{counter_param}",
+            self.label,
+            base32_encode(&self.secret),
+            self.digits
+        );
+        for (key, value) in &self.extra_params {
+            uri.push_str(&format!("&{key}={value}"));
+        }
+        uri
+    }
+
+    /// The RFC 4226 code for `counter`.
+    pub fn hotp(&self, counter: u64) -> String {
+        let counter = counter.to_be_bytes();
+        let hash = match self.algorithm {
+            OtpAlgorithm::Sha1 => hmac::<Hmac<Sha1>>(&self.secret, &counter),
+            OtpAlgorithm::Sha256 => hmac::<Hmac<Sha256>>(&self.secret, &counter),
+            OtpAlgorithm::Sha512 => hmac::<Hmac<Sha512>>(&self.secret, &counter),
+        };
+
+        // dynamic truncation
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        let code = u64::from(binary) % 10u64.pow(self.digits);
+        format!("{code:0width$}", width = self.digits as usize)
+    }
+
+    /// The RFC 6238 code at `timestamp` and the seconds it remains valid, `None` for HOTP.
+    pub fn totp_at(&self, timestamp: u64) -> Option<OtpCode> {
+        let OtpKind::Totp { period } = self.kind else {
+            return None;
+        };
+        Some(OtpCode {
+            code: self.hotp(timestamp / period),
+            valid_for: Some(period - timestamp % period),
+        })
+    }
+}
+
+fn hmac<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32, ignoring case, padding and the spaces authenticator apps like to show.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes().filter(|c| !matches!(c, b'=' | b' ' | b'-')) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    encoded
+}
+
+impl Vault {
+    /// Generates the current code of an entry. HOTP entries move their counter
+    /// forward, so the same code is never handed out twice.
+    pub fn generate_otp(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: &str,
+    ) -> Result<OtpCode, VaultError> {
+        let mut password_list = self.list(vault_key)?;
+        let entry = password_list.find_mut(id_or_name)?;
+        let uri = entry
+            .otp
+            .as_ref()
+            .ok_or_else(|| VaultError::NoOtp(id_or_name.to_string()))?;
+        let mut config = OtpConfig::parse(uri)?;
+
+        match config.kind {
+            OtpKind::Totp { .. } => Ok(config
+                .totp_at(unix_timestamp())
+                .expect("checked to be TOTP")),
+            OtpKind::Hotp { counter } => {
+                // the counter comes from the URI, and wrapping around would reuse codes
+                let next = counter
+                    .checked_add(1)
+                    .ok_or_else(|| VaultError::InvalidOtp("HOTP counter exhausted".to_string()))?;
+                let code = config.hotp(counter);
+                config.kind = OtpKind::Hotp { counter: next };
+                entry.otp = Some(config.to_uri());
+                self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
+                self.save_to_file()?;
+                Ok(OtpCode {
+                    code,
+                    valid_for: None,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B uses a 20, 32 and 64 byte ASCII seed per algorithm
+    const SEED_SHA1: &[u8] = b"12345678901234567890";
+    const SEED_SHA256: &[u8] = b"12345678901234567890123456789012";
+    const SEED_SHA512: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+
+    fn config(kind: OtpKind, seed: &[u8], algorithm: OtpAlgorithm, digits: u32) -> OtpConfig {
+        OtpConfig {
+            kind,
+            label: "test".to_string(),
+            secret: Zeroizing::new(seed.to_vec()),
+            algorithm,
+            digits,
+            extra_params: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226_vectors() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        let hotp = config(
+            OtpKind::Hotp { counter: 0 },
+            SEED_SHA1,
+            OtpAlgorithm::Sha1,
+            6,
+        );
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp.hotp(counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        let vectors = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+        let totp = |seed, algorithm| config(OtpKind::Totp { period: 30 }, seed, algorithm, 8);
+        for (time, sha1, sha256, sha512) in vectors {
+            let code = |seed, algorithm| totp(seed, algorithm).totp_at(time).unwrap().code;
+            assert_eq!(code(SEED_SHA1, OtpAlgorithm::Sha1), sha1);
+            assert_eq!(code(SEED_SHA256, OtpAlgorithm::Sha256), sha256);
+            assert_eq!(code(SEED_SHA512, OtpAlgorithm::Sha512), sha512);
+        }
+        let code = totp(SEED_SHA1, OtpAlgorithm::Sha1).totp_at(59).unwrap();
+        assert_eq!(code.valid_for, Some(1));
+    }
+
+    #[test]
+    fn parses_and_writes_otpauth_uris() {
+        let uri = "otpauth://totp/ACME%20Co:john@example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=ACME%20Co&digits=8&period=60&algorithm=SHA256";
+        let config = OtpConfig::parse(uri).unwrap();
+        assert_eq!(config.kind, OtpKind::Totp { period: 60 });
+        assert_eq!(config.algorithm, OtpAlgorithm::Sha256);
+        assert_eq!(config.digits, 8);
+        assert_eq!(config.secret.as_slice(), SEED_SHA1);
+
+        let reparsed = OtpConfig::parse(&config.to_uri()).unwrap();
+        assert_eq!(reparsed.kind, config.kind);
+        assert_eq!(reparsed.secret, config.secret);
+        assert_eq!(reparsed.label, "ACME%20Co:john@example.com");
+        assert!(reparsed.to_uri().contains("&issuer=ACME%20Co"));
+
+        assert!(OtpConfig::parse("otpauth://hotp/x?secret=GEZDGNBV").is_err());
+        assert!(OtpConfig::parse("otpauth://totp/x?secret=not-base32!").is_err());
+        assert!(OtpConfig::parse("https://example.com").is_err());
+    }
+
+    #[test]
+    fn exhausted_hotp_counter_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = Vault::new(&dir.path().join("test.vault"));
+        let key = vault
+            .initialize(b"master", crate::models::Argon2Params::fast())
+            .unwrap();
+        let mut entry = crate::models::PasswordEntry::new("bank", "john", "hunter2");
+        let uri = format!(
+            "otpauth://hotp/bank?secret=GEZDGNBVGY3TQOJQ&counter={}",
+            u64::MAX - 1
+        );
+        entry.otp = Some(uri);
+        vault.add_entry(&key, entry).unwrap();
+
+        vault.generate_otp(&key, "bank").unwrap();
+        assert!(matches!(
+            vault.generate_otp(&key, "bank"),
+            Err(VaultError::InvalidOtp(_))
+        ));
+        let entry = vault.get_entry(&key, "bank").unwrap();
+        let uri = entry.otp.as_deref().unwrap();
+        assert!(uri.contains(&format!("counter={}", u64::MAX)));
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+use crate::{
+    errors::VaultError,
+    models::{Request, Response},
+};
+
+/// Largest payload either side of the agent socket accepts. Checked before
+/// anything is allocated, so a bogus length prefix can't exhaust memory.
+pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// Bumped whenever `Request` or `Response` change in a way an older peer can't read.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// The oldest protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// From this version on, a connection stays open after the handshake and
+/// carries `ClientMessage`s and `TaggedResponse`s. Before it, it carried a
+/// single bare `Request` and its `Response`.
+pub const TAGGED_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional features a peer announces in its `Hello`, so the other side can
+/// tell what it may use without a version bump for every addition.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Attachments,
+    Otp,
+    Trash,
+    Backups,
+    /// Several requests in flight on one connection, see `TAGGED_PROTOCOL_VERSION`.
+    Pipelining,
+    /// Anything announced by a newer peer that this build doesn't know.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The first message on a connection, sent by the client as `Request::Hello`
+/// and answered by the agent with its own as `Response::Hello`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    /// Package version of the sender, only used in messages to the user.
+    pub software_version: String,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: vec![
+                Capability::Attachments,
+                Capability::Otp,
+                Capability::Trash,
+                Capability::Backups,
+                Capability::Pipelining,
+            ],
+        }
+    }
+
+    /// The newest protocol version both this build and the peer that sent
+    /// `self` speak, if there is one.
+    pub fn negotiate(&self) -> Option<u32> {
+        let version = self.protocol_version.min(PROTOCOL_VERSION);
+        (version >= self.min_protocol_version && version >= MIN_PROTOCOL_VERSION).then_some(version)
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// What a client sends on a connection after the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClientMessage {
+    /// `id` is chosen by the client and must not be reused while the request is in flight.
+    Request { id: u64, request: Box<Request> },
+    /// Drops a request that hasn't started yet or is still waiting for the
+    /// agent, which is then answered with `ResponseError::Cancelled`. Too late
+    /// if it has, it's answered as usual.
+    Cancel { id: u64 },
+}
+
+/// A response together with the ID of the request it answers. Requests on a
+/// connection run in the order they were sent, but cancelled ones are
+/// answered right away, so responses can arrive out of order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaggedResponse {
+    pub id: u64,
+    pub response: Response,
+}
+
+/// The client side of a connection after the handshake, with any number of
+/// requests in flight.
+pub struct Connection<S> {
+    stream: S,
+    next_id: u64,
+    /// Responses that arrived while waiting for a different one.
+    received: HashMap<u64, Response>,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Connection {
+            stream,
+            next_id: 1,
+            received: HashMap::new(),
+        }
+    }
+
+    /// Sends `request` without waiting for its response and returns the ID to receive it by.
+    pub async fn send(&mut self, request: Request) -> Result<u64, VaultError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = ClientMessage::Request {
+            id,
+            request: Box::new(request),
+        };
+        send_message(&mut self.stream, &message).await?;
+        Ok(id)
+    }
+
+    /// Asks the agent to drop request `id`. Its response still has to be received.
+    pub async fn cancel(&mut self, id: u64) -> Result<(), VaultError> {
+        send_message(&mut self.stream, &ClientMessage::Cancel { id }).await
+    }
+
+    /// Waits for the response to request `id`, keeping others that arrive first.
+    pub async fn receive(&mut self, id: u64) -> Result<Response, VaultError> {
+        loop {
+            if let Some(response) = self.received.remove(&id) {
+                return Ok(response);
+            }
+            match receive_message::<_, TaggedResponse>(&mut self.stream).await? {
+                Some(tagged) => {
+                    self.received.insert(tagged.id, tagged.response);
+                }
+                None => {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+        }
+    }
+
+    pub async fn request(&mut self, request: Request) -> Result<Response, VaultError> {
+        let id = self.send(request).await?;
+        self.receive(id).await
+    }
+}
+
+/// Writes `payload` as one frame: its length as a big-endian `u32`, then the bytes.
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> Result<(), VaultError>
+where
+    W: AsyncWrite + Unpin,
+{
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(VaultError::FrameTooLarge(payload.len()));
+    }
+    writer
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads one frame, however many reads it takes to arrive. Returns `None`
+/// if the other side closed the connection cleanly before starting a new one.
+pub async fn read_frame<R>(reader: &mut R) -> Result<Option<Zeroizing<Vec<u8>>>, VaultError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            n => filled += n,
+        }
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(VaultError::FrameTooLarge(len));
+    }
+    let mut payload = Zeroizing::new(vec![0u8; len]);
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// Sends a `Request` or `Response` as a JSON frame. Both may carry secrets,
+/// so the serialized copy is wiped once it's written.
+pub async fn send_message<W, T>(writer: &mut W, message: &T) -> Result<(), VaultError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = Zeroizing::new(serde_json::to_vec(message)?);
+    write_frame(writer, &payload).await
+}
+
+pub async fn receive_message<R, T>(reader: &mut R) -> Result<Option<T>, VaultError>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    match read_frame(reader).await? {
+        Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        errors::ResponseError,
+        models::{EntrySummary, PasswordEntry},
+    };
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn large_messages_survive_partial_reads() {
+        let entries: Vec<EntrySummary> = (0..20_000)
+            .map(|i| EntrySummary::from(&PasswordEntry::new(&format!("site-{i}"), "john", "x")))
+            .collect();
+        let response = Response::EntryList {
+            entries,
+            folders: Vec::new(),
+        };
+
+        // a tiny pipe means every frame arrives over many short reads
+        let (mut client, mut agent) = duplex(61);
+        let writer = tokio::spawn(async move {
+            send_message(&mut agent, &response).await.unwrap();
+            send_message(&mut agent, &Response::Ok).await.unwrap();
+        });
+
+        let Some(Response::EntryList { entries, .. }) =
+            receive_message::<_, Response>(&mut client).await.unwrap()
+        else {
+            panic!("expected an entry list");
+        };
+        assert_eq!(entries.len(), 20_000);
+        assert_eq!(entries[19_999].name, "site-19999");
+        assert!(matches!(
+            receive_message(&mut client).await.unwrap(),
+            Some(Response::Ok)
+        ));
+        writer.await.unwrap();
+        assert!(
+            receive_message::<_, Response>(&mut client)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_are_matched_to_their_requests() {
+        let (client, mut agent) = duplex(1024);
+        // answers everything backwards once it sees a cancellation
+        let agent = tokio::spawn(async move {
+            let mut ids = Vec::new();
+            while let Some(message) = receive_message(&mut agent).await.unwrap() {
+                let cancelled = match message {
+                    ClientMessage::Request { id, .. } => {
+                        ids.push(id);
+                        continue;
+                    }
+                    ClientMessage::Cancel { id } => id,
+                };
+                ids.retain(|id| *id != cancelled);
+                let response = Response::Error(ResponseError::Cancelled);
+                let tagged = TaggedResponse {
+                    id: cancelled,
+                    response,
+                };
+                send_message(&mut agent, &tagged).await.unwrap();
+                for id in ids.drain(..).rev() {
+                    let response = Response::Error(ResponseError::Other(id.to_string()));
+                    send_message(&mut agent, &TaggedResponse { id, response })
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let mut connection = Connection::new(client);
+        let first = connection.send(Request::ListEntries).await.unwrap();
+        let second = connection.send(Request::ListTrash).await.unwrap();
+        let third = connection.send(Request::ListBackups).await.unwrap();
+        connection.cancel(second).await.unwrap();
+
+        for id in [first, third] {
+            let response = connection.receive(id).await.unwrap();
+            assert!(
+                matches!(&response, Response::Error(ResponseError::Other(answered)) if *answered == id.to_string()),
+                "{response:?}"
+            );
+        }
+        assert!(matches!(
+            connection.receive(second).await.unwrap(),
+            Response::Error(ResponseError::Cancelled)
+        ));
+        drop(connection);
+        agent.await.unwrap();
+    }
+
+    #[test]
+    fn hello_negotiates_the_newest_shared_version() {
+        assert_eq!(Hello::current().negotiate(), Some(PROTOCOL_VERSION));
+
+        let newer = Hello {
+            protocol_version: PROTOCOL_VERSION + 3,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            ..Hello::current()
+        };
+        assert_eq!(newer.negotiate(), Some(PROTOCOL_VERSION));
+        let incompatible = Hello {
+            protocol_version: PROTOCOL_VERSION + 3,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::current()
+        };
+        assert_eq!(incompatible.negotiate(), None);
+
+        let hello: Hello = serde_json::from_str(
+            r#"{"protocol_version":9,"min_protocol_version":1,"software_version":"9.0.0","capabilities":["Otp","Teleport"]}"#,
+        )
+        .unwrap();
+        assert_eq!(hello.capabilities, [Capability::Otp, Capability::Unknown]);
+        assert!(!hello.supports(Capability::Trash));
+    }
+
+    #[tokio::test]
+    async fn oversized_and_truncated_frames_are_rejected() {
+        let (mut client, mut agent) = duplex(1024);
+        client
+            .write_all(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_frame(&mut agent).await,
+            Err(VaultError::FrameTooLarge(_))
+        ));
+        assert!(matches!(
+            write_frame(&mut client, &vec![0u8; MAX_FRAME_SIZE + 1]).await,
+            Err(VaultError::FrameTooLarge(_))
+        ));
+
+        let (mut client, mut agent) = duplex(1024);
+        client.write_all(&10u32.to_be_bytes()).await.unwrap();
+        client.write_all(b"short").await.unwrap();
+        drop(client);
+        assert!(matches!(
+            read_frame(&mut agent).await,
+            Err(VaultError::Io(_))
+        ));
+
+        let (mut client, mut agent) = duplex(1024);
+        client.write_all(&[0, 0]).await.unwrap();
+        drop(client);
+        assert!(matches!(
+            read_frame(&mut agent).await,
+            Err(VaultError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    folder::{is_within, normalize_folder},
+    models::{EntrySummary, PasswordEntry, PasswordList, SearchFilters},
+};
+
+const EXACT_NAME: u32 = 100;
+const NAME_PREFIX: u32 = 80;
+const NAME_SUBSTRING: u32 = 60;
+const OTHER_FIELD: u32 = 40;
+/// Fuzzy matches score at most this much, less for every character skipped in between.
+const FUZZY: u32 = 30;
+
+impl From<&PasswordEntry> for EntrySummary {
+    fn from(entry: &PasswordEntry) -> Self {
+        EntrySummary {
+            id: entry.id,
+            name: entry.name.clone(),
+            folder: entry.folder.clone(),
+            username: entry.username.clone(),
+            urls: entry.urls.clone(),
+            tags: entry.tags.clone(),
+            created_at: entry.created_at,
+            modified_at: entry.modified_at,
+        }
+    }
+}
+
+impl SearchFilters {
+    fn matches(&self, entry: &PasswordEntry) -> bool {
+        let contains =
+            |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+
+        self.tags.iter().all(|tag| {
+            let tag = tag.to_lowercase();
+            entry
+                .tags
+                .iter()
+                .any(|entry_tag| entry_tag.to_lowercase() == tag)
+        }) && self
+            .url
+            .as_ref()
+            .is_none_or(|url| entry.urls.iter().any(|entry_url| contains(entry_url, url)))
+            && self
+                .username
+                .as_ref()
+                .is_none_or(|username| contains(&entry.username, username))
+            && self
+                .folder
+                .as_ref()
+                .is_none_or(|folder| is_within(&entry.folder, &normalize_folder(folder)))
+    }
+}
+
+impl PasswordList {
+    /// Returns the entries matching `query` and `filters`, best match first.
+    /// An empty query matches every entry that passes the filters.
+    pub fn search(&self, query: &str, filters: &SearchFilters) -> Vec<EntrySummary> {
+        let query = query.trim().to_lowercase();
+        let mut matches: Vec<(u32, &PasswordEntry)> = self
+            .passwords
+            .iter()
+            .filter(|entry| filters.matches(entry))
+            .filter_map(|entry| Some((score(entry, &query, filters.fuzzy)?, entry)))
+            .collect();
+
+        matches.sort_by(|(a_score, a), (b_score, b)| {
+            b_score.cmp(a_score).then_with(|| a.path().cmp(&b.path()))
+        });
+        matches
+            .into_iter()
+            .map(|(_, entry)| EntrySummary::from(entry))
+            .collect()
+    }
+}
+
+fn score(entry: &PasswordEntry, query: &str, fuzzy: bool) -> Option<u32> {
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    let name = entry.name.to_lowercase();
+    if name == query {
+        return Some(EXACT_NAME);
+    }
+    if name.starts_with(query) {
+        return Some(NAME_PREFIX);
+    }
+    if name.contains(query) {
+        return Some(NAME_SUBSTRING);
+    }
+
+    let path = entry.path();
+    let mut other_fields = [&path, &entry.username]
+        .into_iter()
+        .chain(&entry.urls)
+        .chain(&entry.tags);
+    if other_fields.any(|field| field.to_lowercase().contains(query)) {
+        return Some(OTHER_FIELD);
+    }
+
+    if fuzzy {
+        return fuzzy_score(&name, query);
+    }
+    None
+}
+
+/// Scores `haystack` containing the characters of `needle` in order, the
+/// fewer characters in between the better.
+fn fuzzy_score(haystack: &str, needle: &str) -> Option<u32> {
+    let mut haystack = haystack.chars();
+    let mut skipped = 0;
+    let mut started = false;
+
+    for wanted in needle.chars() {
+        loop {
+            let found = haystack.next()?;
+            if found == wanted {
+                started = true;
+                break;
+            }
+            if started {
+                skipped += 1;
+            }
+        }
+    }
+    Some(FUZZY.saturating_sub(skipped).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list() -> PasswordList {
+        let mut entries = vec![
+            PasswordEntry::new("github", "john", "hunter2"),
+            PasswordEntry::new("github-work", "john.doe", "hunter3"),
+            PasswordEntry::new("gitlab", "jdoe", "hunter4"),
+            PasswordEntry::new("console", "admin", "hunter5"),
+        ];
+        entries[1].tags = vec!["Work".to_string()];
+        entries[2].tags = vec!["Öffentlich".to_string()];
+        entries[1].folder = "work".to_string();
+        entries[3].urls = vec!["https://console.aws.amazon.com".to_string()];
+        entries[3].folder = "work/aws".to_string();
+        PasswordList {
+            passwords: entries,
+            ..PasswordList::default()
+        }
+    }
+
+    fn names(results: Vec<EntrySummary>) -> Vec<String> {
+        results.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn ranks_exact_then_prefix_then_other_fields() {
+        let list = list();
+        let filters = SearchFilters::default();
+        assert_eq!(
+            names(list.search("GitHub", &filters)),
+            ["github", "github-work"]
+        );
+        assert_eq!(names(list.search("aws", &filters)), ["console"]);
+        assert_eq!(names(list.search("", &filters)).len(), 4);
+        assert!(list.search("ghb", &filters).is_empty());
+    }
+
+    #[test]
+    fn fuzzy_matches_characters_in_order() {
+        let list = list();
+        let filters = SearchFilters {
+            fuzzy: true,
+            ..SearchFilters::default()
+        };
+        assert_eq!(
+            names(list.search("ghb", &filters)),
+            ["github", "github-work"]
+        );
+        assert_eq!(names(list.search("glb", &filters)), ["gitlab"]);
+    }
+
+    #[test]
+    fn filters_narrow_the_results() {
+        let list = list();
+        let filters = SearchFilters {
+            tags: vec!["work".to_string()],
+            ..SearchFilters::default()
+        };
+        assert_eq!(names(list.search("", &filters)), ["github-work"]);
+        let filters = SearchFilters {
+            tags: vec!["öffentlich".to_string()],
+            ..SearchFilters::default()
+        };
+        assert_eq!(names(list.search("", &filters)), ["gitlab"]);
+
+        let filters = SearchFilters {
+            folder: Some("work".to_string()),
+            ..SearchFilters::default()
+        };
+        assert_eq!(names(list.search("", &filters)), ["console", "github-work"]);
+        // the root holds the whole tree
+        let filters = SearchFilters {
+            folder: Some("/".to_string()),
+            ..SearchFilters::default()
+        };
+        assert_eq!(names(list.search("", &filters)).len(), 4);
+
+        let filters = SearchFilters {
+            url: Some("amazon".to_string()),
+            username: Some("ADMIN".to_string()),
+            ..SearchFilters::default()
+        };
+        assert_eq!(names(list.search("", &filters)), ["console"]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, DirBuilder},
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::errors::VaultError;
+
+/// Overrides where the agent listens and the client connects, e.g. to use a
+/// socket forwarded over SSH.
+pub const SOCKET_PATH_VAR: &str = "VPASSWORD_AGENT_SOCK";
+
+/// Where the agent socket lives, as both the agent and the client find it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentSocket {
+    pub path: PathBuf,
+    /// The private directory created for the socket. `None` when the location
+    /// was chosen with `VPASSWORD_AGENT_SOCK`, which is left to the user.
+    pub dir: Option<PathBuf>,
+}
+
+/// The user this process runs as, without needing libc.
+pub fn current_uid() -> Result<u32, VaultError> {
+    Ok(fs::metadata("/proc/self")?.uid())
+}
+
+impl AgentSocket {
+    /// `$VPASSWORD_AGENT_SOCK` if set, otherwise `vpassword/agent.sock` in
+    /// `$XDG_RUNTIME_DIR`, or in a per-user directory under the temporary
+    /// directory when there is no runtime directory.
+    pub fn locate() -> Result<AgentSocket, VaultError> {
+        Ok(Self::from_env(
+            env::var_os(SOCKET_PATH_VAR),
+            env::var_os("XDG_RUNTIME_DIR"),
+            current_uid()?,
+        ))
+    }
+
+    fn from_env(path: Option<OsString>, runtime_dir: Option<OsString>, uid: u32) -> AgentSocket {
+        if let Some(path) = path.filter(|path| !path.is_empty()) {
+            return AgentSocket {
+                path: path.into(),
+                dir: None,
+            };
+        }
+        let dir = match runtime_dir.filter(|dir| !dir.is_empty()) {
+            Some(runtime_dir) => PathBuf::from(runtime_dir).join("vpassword"),
+            None => env::temp_dir().join(format!("vpassword-{uid}")),
+        };
+        AgentSocket {
+            path: dir.join("agent.sock"),
+            dir: Some(dir),
+        }
+    }
+
+    /// Where a client records the PID of an agent it started.
+    pub fn pid_file(&self) -> PathBuf {
+        self.path.with_extension("pid")
+    }
+
+    /// Creates the private directory if it doesn't exist yet, then checks it.
+    pub fn prepare(&self, uid: u32) -> Result<(), VaultError> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        match DirBuilder::new().mode(0o700).create(dir) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.check(uid)
+    }
+
+    /// Makes sure nobody but `uid` could have put a socket into the private
+    /// directory, so the agent found there is trustworthy.
+    pub fn check(&self, uid: u32) -> Result<(), VaultError> {
+        match &self.dir {
+            Some(dir) => check_private_dir(dir, uid),
+            None => Ok(()),
+        }
+    }
+}
+
+fn check_private_dir(dir: &Path, uid: u32) -> Result<(), VaultError> {
+    let unsafe_dir =
+        |reason: &str| VaultError::UnsafeSocketDir(format!("{} {reason}", dir.display()));
+    // not following symlinks, a link could point anywhere
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(unsafe_dir("is not a directory"));
+    }
+    if metadata.uid() != uid {
+        return Err(unsafe_dir("belongs to another user"));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(unsafe_dir("is accessible to other users"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    #[test]
+    fn override_wins_over_runtime_dir() {
+        let socket = AgentSocket::from_env(
+            Some("/home/john/.ssh/vpassword.sock".into()),
+            Some("/run/user/1000".into()),
+            1000,
+        );
+        assert_eq!(socket.path, Path::new("/home/john/.ssh/vpassword.sock"));
+        assert_eq!(socket.dir, None);
+
+        let socket = AgentSocket::from_env(Some("".into()), Some("/run/user/1000".into()), 1000);
+        assert_eq!(
+            socket.path,
+            Path::new("/run/user/1000/vpassword/agent.sock")
+        );
+        assert_eq!(
+            socket.pid_file(),
+            Path::new("/run/user/1000/vpassword/agent.pid")
+        );
+
+        let socket = AgentSocket::from_env(None, None, 1000);
+        assert_eq!(socket.dir, Some(env::temp_dir().join("vpassword-1000")));
+    }
+
+    #[test]
+    fn socket_dir_must_be_private() {
+        let runtime_dir = tempfile::tempdir().unwrap();
+        let uid = current_uid().unwrap();
+        let socket = AgentSocket::from_env(None, Some(runtime_dir.path().into()), uid);
+        let dir = socket.dir.clone().unwrap();
+
+        socket.prepare(uid).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        socket.prepare(uid).unwrap();
+        assert!(matches!(
+            socket.check(uid + 1),
+            Err(VaultError::UnsafeSocketDir(_))
+        ));
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(matches!(
+            socket.check(uid),
+            Err(VaultError::UnsafeSocketDir(_))
+        ));
+
+        fs::remove_dir(&dir).unwrap();
+        let elsewhere = tempfile::tempdir().unwrap();
+        symlink(elsewhere.path(), &dir).unwrap();
+        assert!(matches!(
+            socket.prepare(uid),
+            Err(VaultError::UnsafeSocketDir(_))
+        ));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{cmp::Reverse, mem};
+
+use uuid::Uuid;
+
+use crate::{
+    errors::VaultError,
+    models::{EntrySummary, PasswordList, TrashSummary, TrashedEntry, Vault},
+    vault::{find_position, unix_timestamp},
+};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+pub fn default_trash_retention_days() -> u32 {
+    30
+}
+
+fn attachment_ids(entries: &[TrashedEntry]) -> Vec<Uuid> {
+    entries
+        .iter()
+        .flat_map(|trashed| &trashed.entry.attachments)
+        .map(|attachment| attachment.id)
+        .collect()
+}
+
+impl PasswordList {
+    /// Finds a trashed entry the same way [`PasswordList::position`] finds a live one.
+    pub fn trash_position(&self, id_or_name: &str) -> Option<usize> {
+        find_position(self.trash.iter().map(|trashed| &trashed.entry), id_or_name)
+    }
+
+    /// Drops trashed entries that are past the retention period and returns
+    /// the IDs of their attachments, whose files can then be deleted.
+    pub fn purge_expired_trash(&mut self, now: u64) -> Vec<Uuid> {
+        let cutoff = now.saturating_sub(u64::from(self.trash_retention_days) * SECONDS_PER_DAY);
+        let (expired, kept): (Vec<_>, Vec<_>) = mem::take(&mut self.trash)
+            .into_iter()
+            .partition(|trashed| trashed.deleted_at < cutoff);
+        self.trash = kept;
+        attachment_ids(&expired)
+    }
+
+    pub fn restore_from_trash(&mut self, id_or_name: &str) -> Result<(), VaultError> {
+        let index = self
+            .trash_position(id_or_name)
+            .ok_or_else(|| VaultError::NoSuchEntry(id_or_name.to_string()))?;
+        let entry = &self.trash[index].entry;
+        if self.contains(&entry.folder, &entry.name) {
+            return Err(VaultError::DuplicateEntry(entry.path()));
+        }
+
+        let trashed = self.trash.remove(index);
+        self.passwords.push(trashed.entry);
+        Ok(())
+    }
+
+    /// Empties the trash, or removes just `id_or_name` from it, returning the
+    /// IDs of the attachments that went with the purged entries.
+    pub fn purge_trash(&mut self, id_or_name: Option<&str>) -> Result<Vec<Uuid>, VaultError> {
+        let purged = match id_or_name {
+            None => mem::take(&mut self.trash),
+            Some(id_or_name) => {
+                let index = self
+                    .trash_position(id_or_name)
+                    .ok_or_else(|| VaultError::NoSuchEntry(id_or_name.to_string()))?;
+                vec![self.trash.remove(index)]
+            }
+        };
+        Ok(attachment_ids(&purged))
+    }
+}
+
+impl Vault {
+    /// Lists the trash, most recently removed first.
+    pub fn trash(&self, vault_key: &[u8]) -> Result<Vec<TrashSummary>, VaultError> {
+        let password_list = self.list(vault_key)?;
+        let mut trash: Vec<TrashSummary> = password_list
+            .trash
+            .iter()
+            .map(|trashed| TrashSummary {
+                entry: EntrySummary::from(&trashed.entry),
+                deleted_at: trashed.deleted_at,
+            })
+            .collect();
+        trash.sort_by_key(|trashed| Reverse(trashed.deleted_at));
+        Ok(trash)
+    }
+
+    pub fn restore_entry(&mut self, vault_key: &[u8], id_or_name: &str) -> Result<(), VaultError> {
+        self.update_list(vault_key, |list| list.restore_from_trash(id_or_name))
+    }
+
+    /// Returns the number of entries purged.
+    pub fn purge_trash(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: Option<&str>,
+    ) -> Result<usize, VaultError> {
+        let mut count = 0;
+        let purged = self.update_list(vault_key, |list| {
+            let before = list.trash.len();
+            let purged = list.purge_trash(id_or_name)?;
+            count = before - list.trash.len();
+            Ok(purged)
+        })?;
+        self.remove_attachment_files(&purged);
+        Ok(count)
+    }
+
+    /// Purges what is past the retention period, only writing the vault if
+    /// there was something to purge. Returns the number of entries purged.
+    pub fn purge_expired_trash(&mut self, vault_key: &[u8]) -> Result<usize, VaultError> {
+        let mut password_list = self.list(vault_key)?;
+        let before = password_list.trash.len();
+        let purged = password_list.purge_expired_trash(unix_timestamp());
+        let count = before - password_list.trash.len();
+        if count > 0 {
+            self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
+            self.save_to_file()?;
+            self.remove_attachment_files(&purged);
+        }
+        Ok(count)
+    }
+
+    pub fn set_trash_retention(&mut self, vault_key: &[u8], days: u32) -> Result<(), VaultError> {
+        self.update_list(vault_key, |list| {
+            list.trash_retention_days = days;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::PasswordEntry, vault::test_vault};
+
+    #[test]
+    fn removed_entries_can_be_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+
+        vault.remove_entry(&key, "github").unwrap();
+        assert!(vault.list(&key).unwrap().passwords.is_empty());
+        assert_eq!(vault.trash(&key).unwrap()[0].entry.name, "github");
+        assert!(matches!(
+            vault.remove_entry(&key, "github"),
+            Err(VaultError::NoSuchEntry(_))
+        ));
+
+        // a new entry took the name in the meantime
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "jane", "hunter3"))
+            .unwrap();
+        assert!(matches!(
+            vault.restore_entry(&key, "github"),
+            Err(VaultError::DuplicateEntry(_))
+        ));
+        vault.remove_entry(&key, "github").unwrap();
+        vault.purge_trash(&key, None).unwrap();
+        assert!(vault.trash(&key).unwrap().is_empty());
+    }
+
+    #[test]
+    fn trash_is_purged_after_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+        vault.remove_entry(&key, "github").unwrap();
+
+        let mut list = vault.list(&key).unwrap();
+        let now = unix_timestamp();
+        assert!(list.purge_expired_trash(now).is_empty());
+        assert_eq!(list.trash.len(), 1);
+        list.purge_expired_trash(now + 31 * SECONDS_PER_DAY);
+        assert!(list.trash.is_empty());
+
+        assert_eq!(vault.purge_expired_trash(&key).unwrap(), 0);
+        vault.set_trash_retention(&key, 0).unwrap();
+        let mut list = vault.list(&key).unwrap();
+        assert_eq!(list.trash_retention_days, 0);
+        list.purge_expired_trash(now + 1);
+        assert!(list.trash.is_empty());
+    }
+}
@@ -1,20 +1,110 @@
 use crate::{
     errors::VaultError,
-    models::{Argon2Params, EncryptionData, PasswordEntry, PasswordList, Vault},
+    folder::{normalize_folder, split_path, validate_name},
+    kdf::KdfPolicy,
+    keyslot::generate_data_key,
+    models::{
+        Argon2Params, CustomField, EncryptionData, EntryUpdate, KeySlotKind, PasswordEntry,
+        PasswordHistoryItem, PasswordList, TrashedEntry, Vault,
+    },
+    otp::OtpConfig,
 };
 
+use uuid::Uuid;
+
 use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
 use std::{
-    fs::{self, File},
-    path::PathBuf,
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+pub const VAULT_VERSION: u8 = 12;
+pub const PASSWORD_HISTORY_LIMIT: usize = 10;
+
+/// What a failure to open the vault's own key check means once a key slot has
+/// vouched for the key: the file is damaged, the password is not to blame.
+pub(crate) fn damaged_key_check() -> VaultError {
+    VaultError::Corrupted("the part of the vault file that confirms the password".to_string())
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
 impl PasswordEntry {
     pub fn new(name: &str, username: &str, password: &str) -> PasswordEntry {
+        let now = unix_timestamp();
         PasswordEntry {
+            id: Uuid::new_v4(),
             name: name.to_string(),
+            folder: String::new(),
             username: username.to_string(),
             password: password.to_string(),
+            urls: Vec::new(),
+            notes: String::new(),
+            tags: Vec::new(),
+            custom_fields: Vec::new(),
+            otp: None,
+            attachments: Vec::new(),
+            created_at: now,
+            modified_at: now,
+            history: Vec::new(),
+        }
+    }
+
+    /// Replaces the password, remembering the old one in the bounded history.
+    pub fn set_password(&mut self, password: &str) {
+        if self.password == password {
+            return;
+        }
+        let previous = PasswordHistoryItem {
+            password: std::mem::replace(&mut self.password, password.to_string()),
+            changed_at: unix_timestamp(),
+        };
+        self.history.insert(0, previous);
+        self.history.truncate(PASSWORD_HISTORY_LIMIT);
+    }
+}
+
+impl PasswordList {
+    /// Finds an entry by its ID, its `folder/name` path or, when only one
+    /// entry carries it, its bare name.
+    pub fn position(&self, id_or_name: &str) -> Option<usize> {
+        find_position(self.passwords.iter(), id_or_name)
+    }
+
+    pub fn find_mut(&mut self, id_or_name: &str) -> Result<&mut PasswordEntry, VaultError> {
+        match self.position(id_or_name) {
+            Some(index) => Ok(&mut self.passwords[index]),
+            None => Err(VaultError::NoSuchEntry(id_or_name.to_string())),
+        }
+    }
+}
+
+impl EntryUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.username.is_none()
+            && self.password.is_none()
+            && self.urls.is_none()
+            && self.notes.is_none()
+            && self.tags.is_none()
+            && self.otp.is_none()
+            && self.custom_fields.is_empty()
+            && self.removed_fields.is_empty()
+    }
+}
+
+impl CustomField {
+    pub fn new(name: &str, value: &str, sensitive: bool) -> CustomField {
+        CustomField {
+            name: name.to_string(),
+            value: value.to_string(),
+            sensitive,
         }
     }
 }
@@ -24,28 +114,98 @@ impl Vault {
         Self {
             name: path.file_stem().unwrap().to_string_lossy().to_string(),
             path: path.to_owned(),
-            version: 1,
-            argon2: Argon2Params::default(),
+            version: VAULT_VERSION,
+            argon2: None,
+            key_slots: Vec::new(),
+            kdf_policy: None,
+            backup_policy: None,
             encryption: EncryptionData::default(),
+            key_check: None,
+            header_check: None,
         }
     }
 
     pub fn new_from_file(file_path: &PathBuf) -> Result<Vault, VaultError> {
         let file = File::open(file_path)?;
-        let vault: Vault = serde_json::from_reader(file)?;
+        let mut vault: Vault = serde_json::from_reader(file)?;
+        // the file we were opened from is authoritative, not whatever was recorded in it
+        vault.path = file_path.to_owned();
+        vault.check_version()?;
         Ok(vault)
     }
 
-    pub fn unlock_and_get_key(&self, master_password: &[u8]) -> Result<[u8; 32], VaultError> {
-        let vault_key = self.derive_vault_key(master_password)?;
-        self.decrypt_data(&vault_key)?;
+    /// Creates an empty vault under a fresh data key wrapped by `master_password`
+    /// and returns the data key. `argon2` also becomes the vault's minimum KDF policy.
+    pub fn initialize(
+        &mut self,
+        master_password: &[u8],
+        argon2: Argon2Params,
+    ) -> Result<[u8; 32], VaultError> {
+        self.kdf_policy = Some(KdfPolicy::from(&argon2));
+        let data_key = generate_data_key();
+        self.add_key_slot(
+            &data_key,
+            KeySlotKind::MasterPassword,
+            master_password,
+            argon2,
+        )?;
+        self.encrypt_data(
+            &data_key,
+            serde_json::to_string(&PasswordList::default())?.as_bytes(),
+        )?;
+        self.save_to_file()?;
+
+        Ok(data_key)
+    }
+
+    pub fn unlock_and_get_key(&mut self, master_password: &[u8]) -> Result<[u8; 32], VaultError> {
+        self.unlock_with_key_slot(KeySlotKind::MasterPassword, master_password)
+    }
+
+    pub fn unlock_with_key_slot(
+        &mut self,
+        kind: KeySlotKind,
+        secret: &[u8],
+    ) -> Result<[u8; 32], VaultError> {
+        if self.needs_migration() {
+            // older versions only know the master password
+            if kind != KeySlotKind::MasterPassword {
+                return Err(VaultError::NoSuchKeySlot(kind));
+            }
+            return self.migrate(secret);
+        }
+        let data_key = self.unwrap_data_key(kind, secret)?;
+        self.decrypt_data(&data_key).map_err(|e| match e {
+            // the slot vouched for the key, so the data is what's wrong
+            VaultError::Aead => damaged_key_check(),
+            e => e,
+        })?;
+
+        Ok(data_key)
+    }
+
+    /// Re-wraps the data key under `new_password` with a fresh salt. The data
+    /// itself stays encrypted under the same data key, which is returned.
+    pub fn change_master_password(
+        &mut self,
+        old_password: &[u8],
+        new_password: &[u8],
+    ) -> Result<[u8; 32], VaultError> {
+        let data_key = self.unlock_and_get_key(old_password)?;
+        let argon2 = self
+            .key_slot(KeySlotKind::MasterPassword)?
+            .argon2
+            .with_fresh_salt();
+        self.change_header(&data_key, |vault| {
+            vault.add_key_slot(&data_key, KeySlotKind::MasterPassword, new_password, argon2)
+        })?;
+        self.save_to_file()?;
 
-        Ok(vault_key)
+        Ok(data_key)
     }
 
     pub fn delete(&self) -> Result<(), VaultError> {
-        let file_path = format!("{}.vault", self.name.as_str());
-        fs::remove_file(&file_path)?;
+        fs::remove_file(&self.path)?;
         Ok(())
     }
 
@@ -58,64 +218,520 @@ impl Vault {
     pub fn add_entry(
         &mut self,
         vault_key: &[u8],
-        password_entry: PasswordEntry,
+        mut password_entry: PasswordEntry,
     ) -> Result<(), VaultError> {
         let mut password_list = self.list(vault_key)?;
-        if password_list
-            .passwords
-            .iter()
-            .any(|entry| entry.name == password_entry.name)
-        {
-            return Err(VaultError::DuplicateEntry(password_entry.name.clone()));
+        validate_name(&password_entry.name)?;
+        // attachments only come into existence through `add_attachment`
+        password_entry.attachments.clear();
+        if let Some(uri) = &password_entry.otp {
+            OtpConfig::parse(uri)?;
         }
+        password_entry.folder = normalize_folder(&password_entry.folder);
+        if password_list.contains(&password_entry.folder, &password_entry.name) {
+            return Err(VaultError::DuplicateEntry(password_entry.path()));
+        }
+        let now = unix_timestamp();
+        password_entry.id = Uuid::new_v4();
+        password_entry.created_at = now;
+        password_entry.modified_at = now;
         password_list.passwords.push(password_entry);
         self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
         self.save_to_file()?;
 
         Ok(())
     }
-    pub fn remove_entry(&mut self, vault_key: &[u8], name: &str) -> Result<(), VaultError> {
+
+    /// Moves an entry to the trash, from where it can be restored until it is purged.
+    pub fn remove_entry(&mut self, vault_key: &[u8], id_or_name: &str) -> Result<(), VaultError> {
+        let mut password_list = self.list(vault_key)?;
+        let index = password_list
+            .position(id_or_name)
+            .ok_or_else(|| VaultError::NoSuchEntry(id_or_name.to_string()))?;
+        let entry = password_list.passwords.remove(index);
+        password_list.trash.push(TrashedEntry {
+            entry,
+            deleted_at: unix_timestamp(),
+        });
+        let purged = password_list.purge_expired_trash(unix_timestamp());
+
+        self.encrypt_data(
+            vault_key,
+            serde_json::to_string_pretty(&password_list)?.as_bytes(),
+        )?;
+        self.save_to_file()?;
+        self.remove_attachment_files(&purged);
+
+        Ok(())
+    }
+
+    pub fn update_entry(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: &str,
+        update: EntryUpdate,
+    ) -> Result<(), VaultError> {
         let mut password_list = self.list(vault_key)?;
-        if let Some(index) = password_list
-            .passwords
-            .iter()
-            .position(|entry| entry.name == name)
-        {
-            password_list.passwords.remove(index);
+        let entry = password_list.find_mut(id_or_name)?;
+
+        if let Some(username) = &update.username {
+            entry.username = username.clone();
+        }
+        if let Some(password) = &update.password {
+            entry.set_password(password);
+        }
+        if let Some(urls) = &update.urls {
+            entry.urls = urls.clone();
+        }
+        if let Some(notes) = &update.notes {
+            entry.notes = notes.clone();
+        }
+        if let Some(tags) = &update.tags {
+            entry.tags = tags.clone();
+        }
+        match update.otp.as_deref() {
+            None => {}
+            Some("") => entry.otp = None,
+            Some(uri) => {
+                OtpConfig::parse(uri)?;
+                entry.otp = Some(uri.to_string());
+            }
+        }
+        entry
+            .custom_fields
+            .retain(|field| !update.removed_fields.contains(&field.name));
+        for field in &update.custom_fields {
+            match entry
+                .custom_fields
+                .iter_mut()
+                .find(|existing| existing.name == field.name)
+            {
+                Some(existing) => *existing = field.clone(),
+                None => entry.custom_fields.push(field.clone()),
+            }
+        }
+        entry.modified_at = unix_timestamp();
+
+        self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
+        self.save_to_file()?;
 
-            self.encrypt_data(
-                vault_key,
-                serde_json::to_string_pretty(&password_list)?.as_bytes(),
-            )?;
-            self.save_to_file()?;
+        Ok(())
+    }
+
+    /// Makes the `index`th previous password current again. The replaced one
+    /// goes into the history, so a restore can itself be undone.
+    pub fn restore_password(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: &str,
+        index: usize,
+    ) -> Result<(), VaultError> {
+        let mut password_list = self.list(vault_key)?;
+        let entry = password_list.find_mut(id_or_name)?;
+        if index >= entry.history.len() {
+            return Err(VaultError::NoSuchHistoryItem(index));
         }
 
+        let restored = entry.history.remove(index);
+        entry.set_password(&restored.password);
+        entry.modified_at = unix_timestamp();
+
+        self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
+        self.save_to_file()?;
+
         Ok(())
     }
 
-    pub fn get_entry(&mut self, vault_key: &[u8], name: &str) -> Result<PasswordEntry, VaultError> {
+    /// Changes the name while keeping the ID, history and everything else.
+    pub fn rename_entry(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: &str,
+        new_name: &str,
+    ) -> Result<(), VaultError> {
+        let mut password_list = self.list(vault_key)?;
+        validate_name(new_name)?;
+        let entry = password_list.find_mut(id_or_name)?;
+        let folder = entry.folder.clone();
+        if password_list.contains(&folder, new_name) {
+            return Err(VaultError::DuplicateEntry(new_name.to_string()));
+        }
+        let entry = password_list.find_mut(id_or_name)?;
+        entry.name = new_name.to_string();
+        entry.modified_at = unix_timestamp();
+
+        self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
+        self.save_to_file()?;
+
+        Ok(())
+    }
+
+    pub fn get_entry(
+        &mut self,
+        vault_key: &[u8],
+        id_or_name: &str,
+    ) -> Result<PasswordEntry, VaultError> {
         let password_list = self.list(vault_key)?;
-        if let Some(index) = password_list
-            .passwords
-            .iter()
-            .position(|entry| entry.name == name)
-        {
+        if let Some(index) = password_list.position(id_or_name) {
             let entry = password_list.passwords.get(index).unwrap().clone();
             Ok(entry)
         } else {
-            Err(VaultError::NoSuchEntry(name.to_string()))
+            Err(VaultError::NoSuchEntry(id_or_name.to_string()))
         }
     }
 
+    /// Decrypts the entry list, applies `change` and saves the result if it succeeded.
+    pub(crate) fn update_list<T, F>(&mut self, vault_key: &[u8], change: F) -> Result<T, VaultError>
+    where
+        F: FnOnce(&mut PasswordList) -> Result<T, VaultError>,
+    {
+        let mut password_list = self.list(vault_key)?;
+        let result = change(&mut password_list)?;
+        self.encrypt_data(vault_key, serde_json::to_string(&password_list)?.as_bytes())?;
+        self.save_to_file()?;
+        Ok(result)
+    }
+
+    /// Writes the vault, backing up the previous version first.
     pub fn save_to_file(&self) -> Result<(), VaultError> {
+        self.back_up()?;
         let json = serde_json::to_string_pretty(&self)?;
+        write_atomic(&self.path, |file| file.write_all(json.as_ref()))?;
+
+        Ok(())
+    }
+}
 
-        let file_name = format!("{}.vault", self.name.as_str());
+pub(crate) fn find_position<'a, I>(entries: I, id_or_name: &str) -> Option<usize>
+where
+    I: Iterator<Item = &'a PasswordEntry> + Clone,
+{
+    if let Ok(id) = Uuid::parse_str(id_or_name)
+        && let Some(index) = entries.clone().position(|entry| entry.id == id)
+    {
+        return Some(index);
+    }
 
-        let mut file = File::create(file_name)?;
+    let (folder, name) = split_path(id_or_name);
+    if let Some(index) = entries
+        .clone()
+        .position(|entry| entry.folder == folder && entry.name == name)
+    {
+        return Some(index);
+    }
 
-        file.write_all(json.as_ref())?;
+    let mut matches = entries
+        .enumerate()
+        .filter(|(_, entry)| entry.name == id_or_name);
+    match (matches.next(), matches.next()) {
+        (Some((index, _)), None) => Some(index),
+        _ => None,
+    }
+}
 
-        Ok(())
+/// Replaces `path` without ever leaving it half written: the data goes to a
+/// temp file in the same directory, which is fsynced and then renamed over
+/// `path`, followed by an fsync of the directory so the rename itself is durable.
+pub(crate) fn write_atomic<F>(path: &Path, write: F) -> Result<(), VaultError>
+where
+    F: FnOnce(&mut File) -> std::io::Result<()>,
+{
+    let temp_path = temp_path_for(path);
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        write(&mut file)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        File::open(parent_dir(path))?.sync_all()
+    })();
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    parent_dir(path).join(format!(".{file_name}.tmp"))
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// An empty vault at `dir/test.vault` with master password `master`, and its data key.
+#[cfg(test)]
+pub(crate) fn test_vault(dir: &Path) -> (Vault, [u8; 32]) {
+    let mut vault = Vault::new(&dir.join("test.vault"));
+    let key = vault.initialize(b"master", Argon2Params::fast()).unwrap();
+    (vault, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyslot::generate_recovery_key;
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use std::io;
+
+    #[test]
+    fn damaged_key_check_is_not_a_wrong_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, _) = test_vault(dir.path());
+        let key_check = vault.key_check.as_mut().unwrap();
+        let mut ciphertext = STANDARD.decode(&key_check.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        key_check.ciphertext = STANDARD.encode(ciphertext);
+        vault.save_to_file().unwrap();
+
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert!(matches!(
+            reopened.unlock_and_get_key(b"wrong"),
+            Err(VaultError::WrongPassword)
+        ));
+        let error = reopened.unlock_and_get_key(b"master").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "vault is corrupted: the part of the vault file that confirms the password"
+        );
+    }
+
+    #[test]
+    fn saves_to_vault_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+
+        let mut reopened = Vault::new_from_file(&dir.path().join("test.vault")).unwrap();
+        assert_eq!(
+            reopened.get_entry(&key, "github").unwrap().password,
+            "hunter2"
+        );
+        assert!(!temp_path_for(&vault.path).exists());
+
+        vault.delete().unwrap();
+        assert!(!vault.path.exists());
+    }
+
+    #[test]
+    fn change_master_password_rekeys_vault() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+        let old_salt = vault.key_slots[0].argon2.salt.clone();
+
+        assert!(vault.change_master_password(b"wrong", b"new").is_err());
+        let new_key = vault.change_master_password(b"master", b"new").unwrap();
+        assert_eq!(new_key, key);
+        assert_ne!(vault.key_slots[0].argon2.salt, old_salt);
+
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert!(reopened.unlock_and_get_key(b"master").is_err());
+        assert_eq!(reopened.unlock_and_get_key(b"new").unwrap(), new_key);
+        assert_eq!(
+            reopened.get_entry(&new_key, "github").unwrap().password,
+            "hunter2"
+        );
+    }
+
+    #[test]
+    fn every_key_slot_unwraps_the_same_data_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        let recovery_key = generate_recovery_key();
+        vault
+            .change_header(&key, |vault| {
+                vault.add_key_slot(
+                    &key,
+                    KeySlotKind::Keyfile,
+                    b"keyfile contents",
+                    Argon2Params::fast(),
+                )?;
+                vault.add_key_slot(
+                    &key,
+                    KeySlotKind::RecoveryKey,
+                    recovery_key.as_bytes(),
+                    Argon2Params::fast(),
+                )
+            })
+            .unwrap();
+        vault.save_to_file().unwrap();
+
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert_eq!(
+            reopened
+                .unlock_with_key_slot(KeySlotKind::Keyfile, b"keyfile contents")
+                .unwrap(),
+            key
+        );
+        assert_eq!(
+            reopened
+                .unlock_with_key_slot(KeySlotKind::RecoveryKey, recovery_key.as_bytes())
+                .unwrap(),
+            key
+        );
+
+        reopened.remove_key_slot(KeySlotKind::Keyfile).unwrap();
+        reopened.remove_key_slot(KeySlotKind::RecoveryKey).unwrap();
+        assert!(matches!(
+            reopened.remove_key_slot(KeySlotKind::MasterPassword),
+            Err(VaultError::LastKeySlot)
+        ));
+        assert!(matches!(
+            reopened.decrypt_data(&key),
+            Err(VaultError::HeaderTampered)
+        ));
+    }
+
+    #[test]
+    fn update_entry_changes_only_given_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        let mut entry = PasswordEntry::new("github", "john", "hunter2");
+        entry.tags = vec!["work".to_string()];
+        entry.custom_fields = vec![
+            CustomField::new("pin", "1234", true),
+            CustomField::new("team", "infra", false),
+        ];
+        vault.add_entry(&key, entry).unwrap();
+
+        let mut update = EntryUpdate::default();
+        update.password = Some("correct horse".to_string());
+        update.custom_fields = vec![CustomField::new("pin", "4321", true)];
+        update.removed_fields = vec!["team".to_string()];
+        vault.update_entry(&key, "github", update).unwrap();
+
+        let entry = vault.get_entry(&key, "github").unwrap();
+        assert_eq!(entry.username, "john");
+        assert_eq!(entry.password, "correct horse");
+        assert_eq!(entry.tags, ["work"]);
+        assert_eq!(entry.custom_fields.len(), 1);
+        assert_eq!(entry.custom_fields[0].value, "4321");
+        assert!(matches!(
+            vault.update_entry(&key, "gitlab", EntryUpdate::default()),
+            Err(VaultError::NoSuchEntry(_))
+        ));
+    }
+
+    #[test]
+    fn password_changes_are_kept_in_bounded_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "password-0"))
+            .unwrap();
+        for i in 1..=PASSWORD_HISTORY_LIMIT + 2 {
+            let mut update = EntryUpdate::default();
+            update.password = Some(format!("password-{i}"));
+            vault.update_entry(&key, "github", update).unwrap();
+        }
+
+        let entry = vault.get_entry(&key, "github").unwrap();
+        assert_eq!(entry.history.len(), PASSWORD_HISTORY_LIMIT);
+        assert_eq!(
+            entry.history[0].password,
+            format!("password-{}", PASSWORD_HISTORY_LIMIT + 1)
+        );
+
+        vault.restore_password(&key, "github", 1).unwrap();
+        let entry = vault.get_entry(&key, "github").unwrap();
+        assert_eq!(entry.password, format!("password-{PASSWORD_HISTORY_LIMIT}"));
+        assert_eq!(
+            entry.history[0].password,
+            format!("password-{}", PASSWORD_HISTORY_LIMIT + 2)
+        );
+        assert_eq!(entry.history.len(), PASSWORD_HISTORY_LIMIT);
+        assert!(matches!(
+            vault.restore_password(&key, "github", PASSWORD_HISTORY_LIMIT),
+            Err(VaultError::NoSuchHistoryItem(_))
+        ));
+    }
+
+    #[test]
+    fn rename_keeps_id_and_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+        vault
+            .add_entry(&key, PasswordEntry::new("gitlab", "john", "hunter3"))
+            .unwrap();
+        let mut update = EntryUpdate::default();
+        update.password = Some("correct horse".to_string());
+        vault.update_entry(&key, "github", update).unwrap();
+        let id = vault.get_entry(&key, "github").unwrap().id.to_string();
+
+        assert!(matches!(
+            vault.rename_entry(&key, &id, "gitlab"),
+            Err(VaultError::DuplicateEntry(_))
+        ));
+        vault.rename_entry(&key, &id, "github-work").unwrap();
+
+        let entry = vault.get_entry(&key, &id).unwrap();
+        assert_eq!(entry.name, "github-work");
+        assert_eq!(entry.history[0].password, "hunter2");
+        assert_eq!(vault.get_entry(&key, "github-work").unwrap().id, entry.id);
+        assert!(vault.get_entry(&key, "github").is_err());
+    }
+
+    #[test]
+    fn failed_write_keeps_previous_vault() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+
+        let json = serde_json::to_string_pretty(&vault).unwrap();
+        let result = write_atomic(&vault.path, |file| {
+            file.write_all(&json.as_bytes()[..json.len() / 2])?;
+            Err(io::Error::other("simulated crash"))
+        });
+        assert!(result.is_err());
+        assert!(!temp_path_for(&vault.path).exists());
+
+        let mut reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert_eq!(
+            reopened.get_entry(&key, "github").unwrap().password,
+            "hunter2"
+        );
+    }
+
+    #[test]
+    fn leftover_temp_file_does_not_affect_vault() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut vault, key) = test_vault(dir.path());
+
+        // a crash between writing the temp file and renaming it leaves garbage behind
+        let temp_path = temp_path_for(&vault.path);
+        fs::write(&temp_path, b"{\"name\": \"te").unwrap();
+        assert!(
+            Vault::new_from_file(&vault.path)
+                .unwrap()
+                .list(&key)
+                .is_ok()
+        );
+
+        vault
+            .add_entry(&key, PasswordEntry::new("github", "john", "hunter2"))
+            .unwrap();
+        assert!(!temp_path.exists());
+        let reopened = Vault::new_from_file(&vault.path).unwrap();
+        assert_eq!(reopened.list(&key).unwrap().passwords.len(), 1);
     }
 }